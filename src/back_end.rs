@@ -3,4 +3,24 @@ pub mod commands;
 pub mod behaviour;
 pub mod swarm_builder;
 pub mod file_transfer;
-pub mod private_message;
\ No newline at end of file
+pub mod private_message;
+pub mod nickname;
+pub mod state;
+pub mod cli;
+pub mod discovery;
+pub mod peers;
+pub mod persistent_store;
+pub mod transport;
+pub mod blocklist;
+pub mod gossip;
+pub mod resources;
+pub mod throttle;
+pub mod transfers;
+pub mod progress;
+pub mod downloads;
+pub mod manifest;
+pub mod compression;
+pub mod shares;
+pub mod links;
+pub mod preview;
+pub mod wishlist;
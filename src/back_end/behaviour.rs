@@ -1,18 +1,34 @@
 
 use libp2p::{
-    gossipsub, kad, mdns, swarm::NetworkBehaviour,
+    allow_block_list, autonat, connection_limits, memory_connection_limits, dcutr, gossipsub, identify, kad, mdns, ping, relay, rendezvous, swarm::NetworkBehaviour,
 };
-use libp2p::kad::store::MemoryStore;
+use libp2p::swarm::behaviour::toggle::Toggle;
 
 use super::file_transfer::FileTransferBehaviour;
 use super::private_message::PrivateMessageBehaviour;
+use super::persistent_store::PersistentStore;
 
 
 #[derive(NetworkBehaviour)]
 pub struct ChatBehaviour {
+    // refuses connections to and from peers blocked with /block
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    // caps on how many connections we hold, set with --max-connections and friends
+    pub limits: connection_limits::Behaviour,
+    pub memory_limits: memory_connection_limits::Behaviour,
     pub mdns: mdns::tokio::Behaviour,
     pub gossipsub: gossipsub::Behaviour,
-    pub kademlia: kad::Behaviour<MemoryStore>,
+    pub kademlia: kad::Behaviour<PersistentStore>,
     pub file_transfer: FileTransferBehaviour,
     pub private_message: PrivateMessageBehaviour,
+    pub rendezvous: rendezvous::client::Behaviour,
+    // only enabled when this node is run as a rendezvous point with --rendezvous-server
+    pub rendezvous_server: Toggle<rendezvous::server::Behaviour>,
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
+    pub relay_client: relay::client::Behaviour,
+    // only enabled when this node relays for others with --relay-server
+    pub relay_server: Toggle<relay::Behaviour>,
+    pub dcutr: dcutr::Behaviour,
+    pub autonat: autonat::Behaviour,
 }
@@ -0,0 +1,64 @@
+use libp2p::PeerId;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::persistent_store::write_json;
+
+const BLOCKLIST_FILE: &str = "blocked.json";
+
+// Peers the user has blocked with /block. Kept in the data directory so a block survives a restart.
+pub struct Blocklist {
+    path: PathBuf,
+    peers: BTreeSet<PeerId>,
+}
+
+impl Blocklist {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(BLOCKLIST_FILE);
+        let peers = match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Vec<String>>(&bytes) {
+                Ok(peers) => peers.iter().filter_map(|p| PeerId::from_str(p).ok()).collect(),
+                Err(e) => {
+                    eprintln!("Warning: could not read blocked peers, starting empty - {}", e);
+                    BTreeSet::new()
+                }
+            },
+            Err(_) => BTreeSet::new(),
+        };
+        Blocklist { path, peers }
+    }
+
+    fn save(&self) {
+        let peers: Vec<String> = self.peers.iter().map(|p| p.to_string()).collect();
+        if let Err(e) = write_json(&self.path, &peers) {
+            eprintln!("Failed to save blocked peers: {}", e);
+        }
+    }
+
+    // Returns false if the peer was already blocked
+    pub fn block(&mut self, peer_id: PeerId) -> bool {
+        let added = self.peers.insert(peer_id);
+        if added {
+            self.save();
+        }
+        added
+    }
+
+    // Returns false if the peer wasn't blocked
+    pub fn unblock(&mut self, peer_id: &PeerId) -> bool {
+        let removed = self.peers.remove(peer_id);
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains(peer_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+}
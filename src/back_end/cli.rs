@@ -0,0 +1,111 @@
+use clap::Parser;
+use libp2p::Multiaddr;
+use std::path::PathBuf;
+
+use super::downloads::{CollisionPolicy, Layout};
+use super::throttle;
+
+// "off" makes no sense on the command line, just leave the option out
+fn parse_rate(input: &str) -> Result<u64, String> {
+    throttle::parse_rate(input)?.ok_or_else(|| "leave the option out for no limit".to_string())
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Peer-to-peer file bartering and chat")]
+pub struct Cli {
+    /// Peer to connect to on startup, as a multiaddr ending in /p2p/<peer_id>. Can be given more than once
+    #[arg(long = "bootstrap", value_name = "MULTIADDR")]
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// Rendezvous point to register with and discover peers through, as a multiaddr ending in /p2p/<peer_id>
+    #[arg(long = "rendezvous", value_name = "MULTIADDR")]
+    pub rendezvous: Option<Multiaddr>,
+
+    /// An address other nodes can reach us on, e.g. a public IP with a forwarded port. Can be given more than once.
+    /// Without it only addresses confirmed by AutoNAT or a relay are advertised
+    #[arg(long = "external-address", value_name = "MULTIADDR")]
+    pub external_addresses: Vec<Multiaddr>,
+
+    /// Run this node as a rendezvous point that other nodes can register with
+    #[arg(long = "rendezvous-server")]
+    pub rendezvous_server: bool,
+
+    /// Relay to reserve a slot on so peers can reach us from behind a NAT, as a multiaddr ending in /p2p/<peer_id>
+    #[arg(long = "relay", value_name = "MULTIADDR")]
+    pub relay: Option<Multiaddr>,
+
+    /// Relay connections for other nodes, this node should be publicly reachable
+    #[arg(long = "relay-server")]
+    pub relay_server: bool,
+
+    /// Directory where the DHT records and routing table are saved between runs
+    #[arg(long = "data-dir", value_name = "PATH", default_value = "data")]
+    pub data_dir: PathBuf,
+
+    /// Directory of files to share with other peers, can be given more than once. Files are
+    /// shared by their path inside the directory, the first directory wins if two have the same file
+    #[arg(long = "share", value_name = "PATH", default_value = "uploads")]
+    pub shares: Vec<PathBuf>,
+
+    /// Swarm key file for a private network, only nodes with the same key can connect to each other
+    #[arg(long = "swarm-key", value_name = "PATH")]
+    pub swarm_key: Option<PathBuf>,
+
+    /// Most connections to keep open at once, to any peers
+    #[arg(long = "max-connections", value_name = "N", default_value_t = 100)]
+    pub max_connections: u32,
+
+    /// Most connections to keep open to a single peer
+    #[arg(long = "max-connections-per-peer", value_name = "N", default_value_t = 4)]
+    pub max_connections_per_peer: u32,
+
+    /// Most connections that can be in the middle of their handshake at once, in each direction
+    #[arg(long = "max-pending", value_name = "N", default_value_t = 32)]
+    pub max_pending: u32,
+
+    /// Refuse new connections once the process is using this many megabytes of memory.
+    /// Without this, new connections are refused once 90% of the system's memory is in use
+    #[arg(long = "max-memory", value_name = "MB")]
+    pub max_memory: Option<usize>,
+
+    /// Most files to send to other peers at the same time
+    #[arg(long = "max-uploads", value_name = "N", default_value_t = 4)]
+    pub max_uploads: usize,
+
+    /// Most files to download at the same time, the rest wait in the queue
+    #[arg(long = "max-downloads", value_name = "N", default_value_t = 3)]
+    pub max_downloads: usize,
+
+    /// Directory downloaded files are saved in
+    #[arg(long = "download-dir", value_name = "PATH", default_value = "downloads")]
+    pub download_dir: PathBuf,
+
+    /// Save downloads straight into the download directory, or in a folder for each peer
+    #[arg(long = "download-layout", value_enum, default_value_t = Layout::Flat)]
+    pub download_layout: Layout,
+
+    /// What to do when a download has the same name as a file that is already there.
+    /// A download identical to the existing file is never kept twice
+    #[arg(long = "on-collision", value_enum, default_value_t = CollisionPolicy::Rename)]
+    pub on_collision: CollisionPolicy,
+
+    /// Most megabytes of file data to hold in memory for uploads at once
+    #[arg(long = "upload-buffer", value_name = "MB", default_value_t = 256)]
+    pub upload_buffer: u64,
+
+    /// Limit on how fast files are sent to other peers, all together, e.g. 500KB/s
+    #[arg(long = "upload-limit", value_name = "RATE", value_parser = parse_rate)]
+    pub upload_limit: Option<u64>,
+
+    /// Limit on how fast files are downloaded from other peers, all together, e.g. 2MB/s
+    #[arg(long = "download-limit", value_name = "RATE", value_parser = parse_rate)]
+    pub download_limit: Option<u64>,
+
+    /// Don't compress file chunks we send, or ask peers to compress the ones they send us
+    #[arg(long = "no-compression")]
+    pub no_compression: bool,
+
+    /// Write a new random swarm key to the given file and exit
+    #[arg(long = "generate-swarm-key", value_name = "PATH")]
+    pub generate_swarm_key: Option<PathBuf>,
+}
@@ -1,83 +1,219 @@
-use crate::utils::split_string;
+use crate::utils::{self, split_string};
 use crate::behaviour::ChatBehaviour;
-use libp2p::PeerId;
-use libp2p::kad;
+use libp2p::{autonat, Multiaddr, PeerId};
 use std::collections::HashSet;
 use std::error::Error;
 use std::str::FromStr;
+use std::time::Duration;
 use libp2p::gossipsub;
 
-use super::file_transfer::FileRequest;
-use super::private_message::PrivateMessage;
+use super::private_message::{PrivateMessage, PrivateMessageRequest};
+use super::state::ChatState;
+use super::discovery;
+use super::persistent_store;
+use super::throttle::{self, Direction};
+use super::transfers::{Download, Priority, TransferState};
+use super::downloads::{self, CollisionPolicy};
+use super::links::{self, SwapLink};
+use super::preview::PreviewRequest;
+use super::wishlist::{self, Want};
+use clap::ValueEnum;
+use super::nickname::{self, Contacts, Resolved};
 
+// Turns a command argument into a peer id, accepting either a raw peer id or @nickname.
+// Unknown nicknames start a DHT lookup and the command is re-run once it finishes.
+fn resolve_peer(
+    arg: &str,
+    line: &str,
+    swarm: &mut libp2p::Swarm<ChatBehaviour>,
+    contacts: &mut Contacts,
+) -> Option<PeerId> {
+    let Some(name) = arg.strip_prefix('@') else {
+        return match PeerId::from_str(arg) {
+            Ok(pid) => Some(pid),
+            Err(err) => {
+                eprintln!("Invalid Peer ID '{}': {}", arg, err);
+                None
+            }
+        };
+    };
+    match contacts.resolve(name) {
+        Resolved::Peer(peer_id) => Some(peer_id),
+        Resolved::Ambiguous(peers) => {
+            println!("More than one peer uses the nickname {}, use a peer ID instead:", name);
+            for peer_id in peers {
+                println!("{}", peer_id);
+            }
+            None
+        }
+        Resolved::Unknown => {
+            let query_id = swarm.behaviour_mut().kademlia.get_providers(nickname::nickname_provider_key(name));
+            contacts.start_lookup(query_id, name.to_string(), line.to_string());
+            println!("Looking up peers with nickname {}...", name);
+            None
+        }
+    }
+}
 
+// How long we wait for our nickname to be withdrawn from the DHT before exiting anyway
+const EXIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Saves the DHT and exits
+pub fn exit(swarm: &mut libp2p::Swarm<ChatBehaviour>) -> ! {
+    if let Err(e) = persistent_store::save(&mut swarm.behaviour_mut().kademlia) {
+        eprintln!("Failed to save DHT records: {}", e);
+    }
+    std::process::exit(0);
+}
+
+// Withdraws our nickname from the DHT, the event loop exits once that has gone out to our peers
+pub fn begin_exit(swarm: &mut libp2p::Swarm<ChatBehaviour>, state: &mut ChatState) {
+    if state.exit_deadline.is_some() {
+        return;
+    }
+    if swarm.connected_peers().next().is_none() {
+        exit(swarm);
+    }
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    match nickname::unpublish_nickname(kademlia, &state.keypair, &state.nickname) {
+        Ok(query_id) => {
+            println!("Removing your nickname from the network...");
+            state.exit_query = Some(query_id);
+            state.exit_deadline = Some(tokio::time::Instant::now() + EXIT_TIMEOUT);
+        }
+        Err(e) => {
+            eprintln!("Failed to remove nickname record: {:?}", e);
+            exit(swarm);
+        }
+    }
+}
 
 pub fn handle_command(
     line: String,
     swarm: &mut libp2p::Swarm<ChatBehaviour>,
-    self_peer_id: PeerId,
+    state: &mut ChatState,
 ) -> Result<(), Box<dyn Error>> {
     let args = split_string(&line);
-    let kademlia = &mut swarm.behaviour_mut().kademlia;
 
 
-    let cmd = if let Some(cmd) = args.get(0) {
+    let cmd = if let Some(cmd) = args.first() {
         cmd 
     } else {
         println!("No command given");
-        return Ok({});
+        return Ok(());
     };
 
     match cmd.as_str() {
         "/help" => {
             println!("Available commands:");
             println!("/help - Show this help message");
-            println!("/peers - List all connected peers");
+            println!("/peers - List all connected peers with their version, addresses, ping and gossip score");
             println!("/nickname <nickname> - Set your nickname");
             println!("/id - Show your peer ID");
             println!("/join <topic> - Join a topic");
             println!("/topic - List currently subscribed topic");
             println!("/topics - List available topics");
-            println!("/requestfile <peer_id> <filename> - Request a file from a peer");
-            println!("/msg <peer_id> <message> - Send a private message to a peer");
+            println!("/requestfile <peer_id|@nickname> <filename> [low|normal|high] - Request a file from a peer");
+            println!("/requestfiles <peer_id|@nickname> <file|folder>... [low|normal|high] - Request folders or several files from a peer as one transfer");
+            println!("/shares [keywords] - List the files you share, or the ones with all the keywords in their name");
+            println!("/link <shared file> - Make a swap link to one of your files that others can fetch with /get");
+            println!("/get <swap link> [low|normal|high] - Download the file a swap link points to");
+            println!("/preview <peer_id|@nickname> <filename> - See what a file is like before downloading it");
+            println!("/preview <swap link> - See what the file a swap link points to is like");
+            println!("/previews <on|off> <shared file or folder> - Let peers see previews of your files, or stop them");
+            println!("/want <keywords|hash|swap link> - Add a file to your wishlist, peers who have it will offer it to you");
+            println!("/unwant <number> - Take something off your wishlist");
+            println!("/wants - Show your wishlist and what other peers want");
+            println!("/transfers - Show downloads and uploads");
+            println!("/pause <id> - Pause a download");
+            println!("/resume <id> - Resume a paused or failed download");
+            println!("/cancel <id> - Cancel a download");
+            println!("/keep <id> <rename|overwrite|skip> - Decide what to do with a download whose name is already taken");
+            println!("/msg <peer_id|@nickname> <message> - Send a private message to a peer");
+            println!("/sent - Show the delivery status of your private messages");
+            println!("/contacts - List the nicknames of peers you have seen");
+            println!("/relays <on|off> - Show which peer relayed each chat message");
+            println!("/dial <multiaddr> - Connect to a peer by address, e.g. /ip4/1.2.3.4/tcp/4001/p2p/<peer_id>");
+            println!("/nat - Show whether you are reachable, your external addresses and relay status");
+            println!("/block <peer_id|@nickname> - Block a peer, hiding their messages and refusing their connections");
+            println!("/unblock <peer_id|@nickname> - Unblock a peer");
+            println!("/blocked - List blocked peers");
+            println!("/limit <up|down> <rate|off> [peer_id|@nickname] - Limit transfer speed, e.g. /limit up 500KB/s, for everyone or one peer");
+            println!("/limit - Show the current transfer limits");
 
         }
         "/peers" => {
             let peers = swarm.connected_peers();
             println!("Connected peers:");
             for peer in peers {
-                println!("{}", peer);
+                let name = state.contacts.nickname(peer).unwrap_or("unknown");
+                let Some(info) = state.peers.get(peer) else {
+                    println!("{} ({})", peer, name);
+                    continue;
+                };
+                let rtt = info.rtt
+                    .map(|rtt| format!("{}ms", rtt.as_millis()))
+                    .unwrap_or_else(|| "?".to_string());
+                // peers that have been sending us invalid or too many messages have a negative score
+                let score = swarm.behaviour().gossipsub.peer_score(peer)
+                    .map(|score| format!("{:.1}", score))
+                    .unwrap_or_else(|| "?".to_string());
+                println!("{} ({}) - {} {}, ping {}, score {}",
+                    peer,
+                    name,
+                    info.agent_version.as_deref().unwrap_or("unknown agent"),
+                    info.protocol_version.as_deref().unwrap_or(""),
+                    rtt,
+                    score,
+                );
+                for addr in &info.listen_addrs {
+                    println!("    listening on {}", addr);
+                }
             }
         }
         "/nickname" =>{
-            let local_peer_id_record = kad::RecordKey::new(&self_peer_id.to_string());
-            let record = kad::Record {
-                key: local_peer_id_record,
-                value: args[1].as_bytes().to_vec(),
-                publisher: None,
-                expires: None,
+            let Some(new_nickname) = args.get(1) else {
+                println!("Please provide a nickname");
+                return Ok(());
             };
-            kademlia
-                .put_record(record, kad::Quorum::One)
-                .expect("Failed to store record locally.");
+            let kademlia = &mut swarm.behaviour_mut().kademlia;
+            if let Err(e) = nickname::publish_nickname(kademlia, &state.keypair, Some(state.nickname.as_str()), new_nickname) {
+                println!("Failed to publish your nickname, keeping {}: {}", state.nickname, e);
+                return Ok(());
+            }
+            state.nickname = new_nickname.to_string();
+            state.nickname_published = Some(std::time::Instant::now());
         }
         "/id" => {
-            println!("Your peer ID is: {}", self_peer_id);
+            println!("Your peer ID is: {}", state.self_peer_id);
         }
         "/join" => {
-            let gossipsub = &mut swarm.behaviour_mut().gossipsub;
-            let current_topics: Vec<_> = gossipsub.topics().collect();
+            let Some(new_topic) = args.get(1) else {
+                println!("Please provide a topic to join");
+                return Ok(());
+            };
+            if new_topic == wishlist::TOPIC {
+                println!("{} is used for wishlists, please pick another topic", new_topic);
+                return Ok(());
+            }
+            if let Err(e) = discovery::rendezvous_namespace(new_topic) {
+                println!("Can't join: {}", e);
+                return Ok(());
+            }
             //leave original topic first
-            let topic = gossipsub::IdentTopic::new(current_topics[0].to_string());
-            swarm.behaviour_mut().gossipsub.unsubscribe(&topic)?;
-            let topic = gossipsub::IdentTopic::new(args.get(1).expect("No topic given"));
+            if let Some(current_topic) = discovery::current_topic(swarm) {
+                let topic = gossipsub::IdentTopic::new(current_topic);
+                swarm.behaviour_mut().gossipsub.unsubscribe(&topic)?;
+                discovery::rendezvous_unregister(swarm, state, &topic.to_string());
+            }
+            let topic = gossipsub::IdentTopic::new(new_topic);
             swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+            discovery::rendezvous_register(swarm, state, &topic.to_string());
             println!("Joined topic: {}", topic);
         }
         "/topic" => {
-            let topics = swarm.behaviour_mut().gossipsub.topics();
             println!("Currently subsribed topic:");
-            for topic in topics {
+            if let Some(topic) = discovery::current_topic(swarm) {
                 println!("{}", topic);
             }
         }
@@ -92,52 +228,487 @@ pub fn handle_command(
             //test if filename and peer id are provided
             if args.len() < 3 {
                 println!("Please provide a peer ID and a filename");
-                return Ok({});
+                return Ok(());
             }
-            let file_transfer = &mut swarm.behaviour_mut().file_transfer;
-            let peer_id_str = &args[1];
-
-
-            let peer_id = match PeerId::from_str(peer_id_str) {
-                Ok(pid) => pid,
-                Err(err) => {
-                    eprintln!("Invalid Peer ID '{}': {}", peer_id_str, err);
+            let Some(peer_id) = resolve_peer(&args[1], &line, swarm, &mut state.contacts) else {
+                return Ok(());
+            };
+            let priority = match args.get(3).map(|p| p.parse::<Priority>()) {
+                Some(Ok(priority)) => priority,
+                Some(Err(e)) => {
+                    println!("{}", e);
                     return Ok(());
                 }
+                None => Priority::Normal,
             };
             let filename = &args[2];
-            let request = FileRequest(filename.to_string());
-            file_transfer.send_request(peer_id, request)?;
-            println!("Sent file request for {} to {}", filename, peer_id);
+            // "." or ".." would end up saved outside the download folder
+            if !links::is_file_name(&downloads::saved_name(filename)) {
+                println!("{:?} is not a file name that can be saved", filename);
+                return Ok(());
+            }
+            let id = swarm.behaviour_mut().file_transfer.queue_download(peer_id, filename.to_string(), priority, state);
+            println!("Queued transfer #{} of {} from {}", id, filename, peer_id);
+        }
+        "/requestfiles" => {
+            if args.len() < 3 {
+                println!("Please provide a peer ID and at least one file or folder");
+                return Ok(());
+            }
+            let Some(peer_id) = resolve_peer(&args[1], &line, swarm, &mut state.contacts) else {
+                return Ok(());
+            };
+            let mut paths: Vec<String> = args[2..].iter().map(|p| p.trim_matches('"').to_string()).collect();
+            // a priority on the end, as long as it isn't the only thing asked for
+            let priority = match paths.last().map(|p| p.parse::<Priority>()) {
+                Some(Ok(priority)) if paths.len() > 1 => {
+                    paths.pop();
+                    priority
+                }
+                _ => Priority::Normal,
+            };
+            let id = swarm.behaviour_mut().file_transfer.queue_group(peer_id, paths.clone(), priority, state);
+            println!("Asked {} for the list of files in {}, transfer #{}", peer_id, paths.join(", "), id);
         }
 
         "/msg" => {
-            let private_message = &mut swarm.behaviour_mut().private_message;
-            let peer_id_str = &args[1];
-            let peer_id = match PeerId::from_str(peer_id_str) {
-                Ok(pid) => pid,
-                Err(err) => {
-                    eprintln!("Invalid Peer ID '{}': {}", peer_id_str, err);
-                    return Ok(());
-                }
+            if args.len() < 3 {
+                println!("Please provide a peer ID and a message");
+                return Ok(());
+            }
+            let Some(peer_id) = resolve_peer(&args[1], &line, swarm, &mut state.contacts) else {
+                return Ok(());
             };
-            let self_peer_id_str = self_peer_id.to_string();
+            let private_message = &mut swarm.behaviour_mut().private_message;
+            let self_peer_id_str = state.self_peer_id.to_string();
             let message = args[2..].join(" ");
+            let id = state.message_tracker.next_id();
             let priv_message = PrivateMessage {
+                id,
                 sender: self_peer_id_str,
-                message,
+                message: message.clone(),
             };
-            match private_message.send_request(peer_id, priv_message) {
-                Ok(_) => println!("Sent private message to {}", peer_id),
+            match private_message.send_request(peer_id, PrivateMessageRequest::Message(priv_message)) {
+                Ok(request_id) => {
+                    state.message_tracker.track(request_id, id, peer_id, message);
+                    println!("Sent private message #{} to {}", id, peer_id);
+                }
                 Err(e) => eprintln!("Failed to send private message to {}: {:?}", peer_id, e),
             }
         }
+        "/sent" => {
+            println!("Sent private messages:");
+            for (id, sent) in state.message_tracker.sent() {
+                println!("#{} to {} [{}]: {}", id, sent.peer_id, sent.status, sent.message);
+            }
+        }
+        "/contacts" => {
+            println!("Known contacts:");
+            for (peer_id, name) in state.contacts.iter() {
+                println!("{} - {}", name, peer_id);
+            }
+        }
+        "/relays" => {
+            match args.get(1).map(String::as_str) {
+                Some("on") => state.show_relays = true,
+                Some("off") => state.show_relays = false,
+                _ => {
+                    println!("Please use /relays on or /relays off");
+                    return Ok(());
+                }
+            }
+            println!("Showing relays: {}", state.show_relays);
+        }
+        "/dial" => {
+            let Some(addr) = args.get(1) else {
+                println!("Please provide a multiaddr to dial");
+                return Ok(());
+            };
+            let addr: Multiaddr = match addr.parse() {
+                Ok(addr) => addr,
+                Err(err) => {
+                    eprintln!("Invalid multiaddr '{}': {}", addr, err);
+                    return Ok(());
+                }
+            };
+            match discovery::dial_address(swarm, addr.clone()) {
+                Ok(()) => {
+                    println!("Dialing {}", addr);
+                    // with a new peer in the routing table, look for more peers through it
+                    if discovery::peer_id_from_addr(&addr).is_some() {
+                        let _ = swarm.behaviour_mut().kademlia.bootstrap();
+                    }
+                }
+                Err(e) => eprintln!("Failed to dial {}: {}", addr, e),
+            }
+        }
+        "/nat" => {
+            match swarm.behaviour().autonat.nat_status() {
+                autonat::NatStatus::Public(addr) => println!("NAT status: publicly reachable at {}", addr),
+                autonat::NatStatus::Private => println!("NAT status: behind a NAT"),
+                autonat::NatStatus::Unknown => println!("NAT status: unknown, still probing"),
+            }
+            println!("External addresses:");
+            for addr in swarm.external_addresses() {
+                println!("{}", addr);
+            }
+            match state.relay_reservation {
+                Some(relay_peer) => println!("Relay: reserved on {}", relay_peer),
+                None => println!("Relay: no reservation"),
+            }
+        }
+        "/block" => {
+            let Some(arg) = args.get(1) else {
+                println!("Please provide a peer ID or @nickname to block");
+                return Ok(());
+            };
+            let Some(peer_id) = resolve_peer(arg, &line, swarm, &mut state.contacts) else {
+                return Ok(());
+            };
+            if peer_id == state.self_peer_id {
+                println!("You can't block yourself");
+                return Ok(());
+            }
+            if !state.blocklist.block(peer_id) {
+                println!("{} is already blocked", state.contacts.display_name(&peer_id));
+                return Ok(());
+            }
+            // closes any open connections to the peer and refuses new ones
+            swarm.behaviour_mut().blocked.block_peer(peer_id);
+            swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+            // stop Kademlia from dialing them again
+            swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+            println!("Blocked {}", state.contacts.display_name(&peer_id));
+        }
+        "/unblock" => {
+            let Some(arg) = args.get(1) else {
+                println!("Please provide a peer ID or @nickname to unblock");
+                return Ok(());
+            };
+            let Some(peer_id) = resolve_peer(arg, &line, swarm, &mut state.contacts) else {
+                return Ok(());
+            };
+            if !state.blocklist.unblock(&peer_id) {
+                println!("{} is not blocked", state.contacts.display_name(&peer_id));
+                return Ok(());
+            }
+            swarm.behaviour_mut().blocked.unblock_peer(peer_id);
+            swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer_id);
+            println!("Unblocked {}", state.contacts.display_name(&peer_id));
+        }
+        "/blocked" => {
+            println!("Blocked peers:");
+            for peer_id in state.blocklist.iter() {
+                println!("{} - {}", state.contacts.display_name(peer_id), peer_id);
+            }
+        }
+        "/limit" => {
+            if args.len() == 1 {
+                for direction in [Direction::Up, Direction::Down] {
+                    match state.throttle.global_limit(direction) {
+                        Some(rate) => println!("{}: {}", direction, throttle::format_rate(rate)),
+                        None => println!("{}: no limit", direction),
+                    }
+                }
+                for (peer_id, direction, rate) in state.throttle.peer_limits() {
+                    println!("{} for {}: {}", direction, state.contacts.display_name(peer_id), throttle::format_rate(rate));
+                }
+                return Ok(());
+            }
+            let direction = match args[1].as_str() {
+                "up" => Direction::Up,
+                "down" => Direction::Down,
+                _ => {
+                    println!("Please use /limit up <rate> or /limit down <rate>");
+                    return Ok(());
+                }
+            };
+            let Some(rate) = args.get(2) else {
+                println!("Please provide a rate, e.g. 500KB/s, or off");
+                return Ok(());
+            };
+            let rate = match throttle::parse_rate(rate) {
+                Ok(rate) => rate,
+                Err(e) => {
+                    println!("{}", e);
+                    return Ok(());
+                }
+            };
+            let peer = match args.get(3) {
+                Some(arg) => match resolve_peer(arg, &line, swarm, &mut state.contacts) {
+                    Some(peer_id) => Some(peer_id),
+                    None => return Ok(()),
+                },
+                None => None,
+            };
+            state.throttle.set_limit(direction, peer, rate);
+            let who = peer.map(|p| format!(" for {}", state.contacts.display_name(&p))).unwrap_or_default();
+            match rate {
+                Some(rate) => println!("Limited {}{} to {}", direction, who, throttle::format_rate(rate)),
+                None => println!("Removed the {} limit{}", direction, who),
+            }
+        }
+        "/shares" => {
+            let keywords = &args[1..];
+            let mut count = 0;
+            for (name, file) in state.shares.search(keywords) {
+                let hash = match &file.hash {
+                    Some(hash) => &hash[..16],
+                    None => "not hashed yet",
+                };
+                println!("{} - {} - {}", name, utils::format_size(file.size), hash);
+                count += 1;
+            }
+            println!("{} shared files", count);
+            if state.shares.hashing() > 0 {
+                println!("{} files still being hashed", state.shares.hashing());
+            }
+        }
+        "/link" => {
+            let Some(name) = args.get(1).map(|n| n.trim_matches('"')) else {
+                println!("Please provide the name of a shared file, see /shares");
+                return Ok(());
+            };
+            let Some(file) = state.shares.get(name) else {
+                println!("You don't share a file called {}", name);
+                return Ok(());
+            };
+            let Some(hash) = &file.hash else {
+                println!("{} hasn't been hashed yet, try again in a moment", name);
+                return Ok(());
+            };
+            // only the file name goes in the link, not the folders it is in
+            let filename = name.rsplit('/').next().unwrap_or(name);
+            let link = SwapLink { hash: hash.clone(), size: file.size, provider: Some(state.self_peer_id), name: filename.to_string() };
+            println!("{}", link);
+        }
+        "/get" => {
+            let Some(link) = args.get(1) else {
+                println!("Please provide a swap link, e.g. /get swap://<hash>/<size>/<peer_id>/<name>");
+                return Ok(());
+            };
+            let link = match link.parse::<SwapLink>() {
+                Ok(link) => link,
+                Err(e) => {
+                    println!("Invalid swap link: {}", e);
+                    return Ok(());
+                }
+            };
+            let priority = match args.get(2).map(|p| p.parse::<Priority>()) {
+                Some(Ok(priority)) => priority,
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    return Ok(());
+                }
+                None => Priority::Normal,
+            };
+            if let Some((name, _)) = state.shares.find_hash(&link.hash) {
+                println!("You already have this file, it is shared as {}", name);
+                return Ok(());
+            }
+            let Some(peer_id) = link.provider else {
+                println!("The link doesn't say which peer has the file");
+                return Ok(());
+            };
+            let id = swarm.behaviour_mut().file_transfer.queue_link(peer_id, &link, priority, state);
+            println!("Queued transfer #{} of {} ({}) from {}", id, link.name, utils::format_size(link.size), peer_id);
+        }
+        "/preview" => {
+            let request = match (args.get(1), args.get(2)) {
+                (Some(link), None) => match link.parse::<SwapLink>() {
+                    Ok(link) => link.provider.map(|peer_id| (peer_id, PreviewRequest { name: link.name, hash: Some(link.hash) })),
+                    Err(e) => {
+                        println!("Invalid swap link: {}", e);
+                        return Ok(());
+                    }
+                },
+                (Some(peer), Some(name)) => {
+                    let Some(peer_id) = resolve_peer(peer, &line, swarm, &mut state.contacts) else {
+                        return Ok(());
+                    };
+                    Some((peer_id, PreviewRequest { name: name.trim_matches('"').to_string(), hash: None }))
+                }
+                (None, _) => {
+                    println!("Please provide a peer ID and a filename, or a swap link");
+                    return Ok(());
+                }
+            };
+            let Some((peer_id, request)) = request else {
+                println!("The link doesn't say which peer has the file");
+                return Ok(());
+            };
+            println!("Asking {} for a preview of {}", state.contacts.display_name(&peer_id), request.name);
+            swarm.behaviour_mut().file_transfer.preview.send_request(&peer_id, request);
+        }
+        "/previews" => {
+            let (Some(setting), Some(name)) = (args.get(1), args.get(2)) else {
+                println!("Please use /previews on <file or folder> or /previews off <file or folder>");
+                return Ok(());
+            };
+            let preview = match setting.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    println!("Please use /previews on <file or folder> or /previews off <file or folder>");
+                    return Ok(());
+                }
+            };
+            match state.shares.set_preview(name.trim_matches('"'), preview) {
+                0 => println!("You don't share a file or folder called {}", name),
+                count => println!("Previews turned {} for {} files", setting, count),
+            }
+        }
+        "/want" => {
+            let want = match Want::parse(&args[1..]) {
+                Ok(want) => want,
+                Err(e) => {
+                    println!("{}", e);
+                    return Ok(());
+                }
+            };
+            match state.wishlist.add(want.clone()) {
+                Ok(()) => {
+                    println!("Added {} to your wishlist", want);
+                    // let peers know soon rather than at the next broadcast
+                    state.wishlist.schedule_broadcast();
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+        "/unwant" => {
+            let Some(number) = args.get(1).and_then(|n| n.parse::<usize>().ok()) else {
+                println!("Please give the number of the wish to remove, see /wants");
+                return Ok(());
+            };
+            match state.wishlist.remove(number) {
+                Some(want) => {
+                    println!("Removed {} from your wishlist", want);
+                    state.wishlist.schedule_broadcast();
+                }
+                None => println!("There is no wish number {}, see /wants", number),
+            }
+        }
+        "/wants" => {
+            println!("Your wishlist:");
+            for (n, want) in state.wishlist.wants().iter().enumerate() {
+                println!("{}. {}", n + 1, want);
+            }
+            println!("Wanted by peers:");
+            for (peer, wants) in state.wishlist.peer_wants() {
+                let wants: Vec<String> = wants.iter().map(|w| w.to_string()).collect();
+                println!("{} - {}", state.contacts.display_name(peer), wants.join(", "));
+            }
+        }
+        "/transfers" => {
+            println!("Downloads:");
+            let print_download = |id: &u64, download: &Download, indent: &str| {
+                let progress = match download.total {
+                    Some(total) => format!("{}/{} bytes", download.received, total),
+                    None => format!("{} bytes", download.received),
+                };
+                println!("{}#{} {} from {} [{}] {}, {} priority",
+                    indent,
+                    id,
+                    download.filename,
+                    state.contacts.display_name(&download.peer),
+                    download.state,
+                    progress,
+                    download.priority,
+                );
+            };
+            for (id, download) in state.transfers.iter().filter(|(_, d)| d.group.is_none()) {
+                print_download(id, download, "");
+            }
+            // folders and sets of files, with the files in them underneath
+            for (id, group) in state.transfers.groups() {
+                let done = group.files.iter()
+                    .filter(|file| state.transfers.get(**file).is_some_and(|d| d.state == TransferState::Completed))
+                    .count();
+                println!("#{} {} from {} [{}] {}/{} files, {}/{} bytes, {} priority",
+                    id,
+                    group.name,
+                    state.contacts.display_name(&group.peer),
+                    group.state,
+                    done,
+                    group.files.len(),
+                    group.received,
+                    group.total,
+                    group.priority,
+                );
+                for file in &group.files {
+                    if let Some(download) = state.transfers.get(*file) {
+                        print_download(file, download, "    ");
+                    }
+                }
+            }
+            println!("Uploads:");
+            for (peer_id, filename, upload) in state.upload_slots.iter() {
+                println!("{} to {} - {}/{} bytes", filename, state.contacts.display_name(peer_id), upload.sent, upload.total);
+            }
+            match state.compression {
+                true => {
+                    println!("Compression:");
+                    println!("sent {}", state.compression_stats.sent_summary());
+                    println!("received {}", state.compression_stats.received_summary());
+                }
+                false => println!("Compression: off"),
+            }
+        }
+        "/pause" | "/resume" | "/cancel" => {
+            let Some(id) = args.get(1).and_then(|id| id.trim_start_matches('#').parse::<u64>().ok()) else {
+                println!("Please provide a transfer number, see /transfers");
+                return Ok(());
+            };
+            let result = match cmd.as_str() {
+                "/pause" => state.transfers.pause(id),
+                "/resume" => state.transfers.resume(id),
+                _ => state.transfers.cancel(id).map(|parts| {
+                    // nothing else will finish the files, so don't leave half of them behind
+                    for part in parts {
+                        let _ = std::fs::remove_file(part);
+                    }
+                }),
+            };
+            match result {
+                Ok(()) => {
+                    println!("Transfer #{} {}", id, state.transfers.describe(id));
+                    // a slot may have opened up, or a resumed download may be able to start
+                    swarm.behaviour_mut().file_transfer.start_queued(state);
+                }
+                Err(e) => println!("Can't {} transfer #{}: {}", &cmd[1..], id, e),
+            }
+        }
+        "/keep" => {
+            let (Some(id), Some(policy)) = (
+                args.get(1).and_then(|id| id.trim_start_matches('#').parse::<u64>().ok()),
+                args.get(2).and_then(|p| CollisionPolicy::from_str(p, true).ok()),
+            ) else {
+                println!("Please use /keep <id> rename, /keep <id> overwrite or /keep <id> skip");
+                return Ok(());
+            };
+            let Some(download) = state.transfers.get_mut(id) else {
+                println!("No transfer #{}", id);
+                return Ok(());
+            };
+            let TransferState::Conflict(_) = download.state else {
+                println!("Transfer #{} is {}", id, download.state);
+                return Ok(());
+            };
+            if policy == CollisionPolicy::Ask {
+                println!("Please pick rename, overwrite or skip");
+                return Ok(());
+            }
+            // comparing it with the file in the way can take a while, the result is printed when it's done
+            let elapsed = download.meter.elapsed();
+            state.transfers.finish_in_background(id, policy, true, elapsed);
+        }
         "/exit" => {
-            std::process::exit(0);
+            begin_exit(swarm, state);
         }
         _=> {
             println!("Unexpected command");
         }
     }
-    Ok({})
+    Ok(())
 }
@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use super::utils::format_size;
+
+// zstd level, low enough that compressing a chunk costs less than sending the bytes it saves
+const LEVEL: i32 = 3;
+
+// Files that are already compressed, squeezing them again only wastes time on both ends
+const COMPRESSED_EXTENSIONS: [&str; 34] = [
+    "zip", "gz", "tgz", "bz2", "xz", "txz", "zst", "7z", "rar", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "ogg", "opus", "flac", "aac", "m4a",
+    "mp4", "mkv", "webm", "avi", "mov",
+    "pdf", "docx", "xlsx", "epub", "apk",
+];
+
+// The ways a chunk can be compressed. On the wire they are sent by name, so a peer that
+// knows of other ones can still talk to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+// What we tell peers we can take, empty when compression is turned off
+pub fn supported(enabled: bool) -> Vec<String> {
+    match enabled {
+        true => vec![Codec::Zstd.name().to_string()],
+        false => Vec::new(),
+    }
+}
+
+// Picks a codec both sides support for a file, if it is worth compressing at all
+pub fn choose(offered: &[String], filename: &str, enabled: bool) -> Option<Codec> {
+    if !enabled || is_compressed_format(filename) {
+        return None;
+    }
+    offered.iter().find_map(|name| Codec::from_name(name))
+}
+
+fn is_compressed_format(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.as_str()))
+}
+
+// Compresses a chunk, or None if that wouldn't make it noticeably smaller
+pub fn compress(codec: Codec, data: &[u8]) -> Option<Vec<u8>> {
+    let packed = match codec {
+        Codec::Zstd => zstd::bulk::compress(data, LEVEL).ok()?,
+    };
+    // under 5% smaller isn't worth the other side unpacking it
+    (packed.len() < data.len() - data.len() / 20).then_some(packed)
+}
+
+// Unpacks a chunk, refusing to make it any bigger than `max_len` so a peer can't send
+// something small that unpacks into something huge
+pub fn decompress(codec: &str, data: &[u8], max_len: usize) -> Result<Vec<u8>, String> {
+    match Codec::from_name(codec) {
+        Some(Codec::Zstd) => zstd::bulk::decompress(data, max_len).map_err(|e| e.to_string()),
+        None => Err(format!("unknown compression {}", codec)),
+    }
+}
+
+// How much compression has saved, for everything sent and received since we started
+#[derive(Default)]
+pub struct CompressionStats {
+    // file data before compression
+    pub sent: u64,
+    // and what actually went over the wire
+    pub sent_wire: u64,
+    pub received: u64,
+    pub received_wire: u64,
+}
+
+impl CompressionStats {
+    pub fn sent_summary(&self) -> String {
+        summary(self.sent, self.sent_wire)
+    }
+
+    pub fn received_summary(&self) -> String {
+        summary(self.received, self.received_wire)
+    }
+}
+
+fn summary(raw: u64, wire: u64) -> String {
+    if raw == 0 {
+        return "nothing yet".to_string();
+    }
+    let saved = raw.saturating_sub(wire);
+    format!("{} as {}, saved {} ({}%)", format_size(raw), format_size(wire), format_size(saved), saved * 100 / raw)
+}
@@ -0,0 +1,118 @@
+use libp2p::multiaddr::Protocol;
+use libp2p::{rendezvous, Multiaddr, PeerId, Swarm};
+use std::error::Error;
+use std::time::Duration;
+
+use super::behaviour::ChatBehaviour;
+use super::state::ChatState;
+use super::wishlist;
+
+// How often we ask the rendezvous point for new peers in our topic
+pub const RENDEZVOUS_DISCOVER_INTERVAL: Duration = Duration::from_secs(30);
+// How long to wait before trying again when the rendezvous point turns a registration down
+pub const RENDEZVOUS_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Pulls the peer id out of a multiaddr ending in /p2p/<peer_id>
+pub fn peer_id_from_addr(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Some(peer_id),
+        _ => None,
+    }
+}
+
+// Dials an address, and if it names the peer, tells Kademlia about it so it can be used for lookups
+pub fn dial_address(swarm: &mut Swarm<ChatBehaviour>, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
+    if let Some(peer_id) = peer_id_from_addr(&addr) {
+        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+    }
+    swarm.dial(addr)?;
+    Ok(())
+}
+
+// Connects to the configured bootstrap peers and starts a Kademlia bootstrap, so we can find
+// peers outside of the local network that mDNS can see
+pub fn bootstrap(swarm: &mut Swarm<ChatBehaviour>, peers: &[Multiaddr]) {
+    for addr in peers {
+        if peer_id_from_addr(addr).is_none() {
+            eprintln!("Bootstrap address {addr} has no /p2p/<peer_id>, it will be dialled but not used for Kademlia");
+        }
+        if let Err(e) = dial_address(swarm, addr.clone()) {
+            eprintln!("Failed to dial bootstrap peer {addr}: {e}");
+        }
+    }
+    if !peers.is_empty() {
+        if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
+            eprintln!("Failed to start Kademlia bootstrap: {e}");
+        }
+    }
+}
+
+// Each chat topic gets its own rendezvous namespace, so peers only meet others trading in the same topic.
+// Namespaces are limited to 255 bytes, which a long topic name can go over
+pub fn rendezvous_namespace(topic: &str) -> Result<rendezvous::Namespace, String> {
+    rendezvous::Namespace::new(format!("swap-bytes/{topic}")).map_err(|_| format!("the topic name {topic} is too long"))
+}
+
+// The chat topic we are in, every node is also subscribed to the wishlist topic alongside it
+pub fn current_topic(swarm: &Swarm<ChatBehaviour>) -> Option<String> {
+    swarm.behaviour().gossipsub.topics().map(|topic| topic.to_string()).find(|topic| topic != wishlist::TOPIC)
+}
+
+// Registers us under the topic's namespace at the rendezvous point and asks who else is there
+pub fn rendezvous_register(swarm: &mut Swarm<ChatBehaviour>, state: &mut ChatState, topic: &str) {
+    let Some(point) = state.rendezvous_point else {
+        return;
+    };
+    let namespace = match rendezvous_namespace(topic) {
+        Ok(namespace) => namespace,
+        Err(e) => {
+            eprintln!("Not registering with the rendezvous point: {e}");
+            return;
+        }
+    };
+    rendezvous_renew(swarm, state);
+    // a new namespace means the old cookie no longer applies
+    state.rendezvous_cookie = None;
+    swarm.behaviour_mut().rendezvous.discover(Some(namespace), None, None, point);
+}
+
+// Registers again before the registration runs out, and once we know an address others can reach us on
+pub fn rendezvous_renew(swarm: &mut Swarm<ChatBehaviour>, state: &mut ChatState) {
+    state.rendezvous_renew_at = None;
+    let (Some(point), Some(topic)) = (state.rendezvous_point, current_topic(swarm)) else {
+        return;
+    };
+    if !swarm.is_connected(&point) {
+        return;
+    }
+    let Ok(namespace) = rendezvous_namespace(&topic) else {
+        return;
+    };
+    match swarm.behaviour_mut().rendezvous.register(namespace, point, None) {
+        Ok(()) => {}
+        // we register as soon as AutoNAT or a relay gives us an address
+        Err(rendezvous::client::RegisterError::NoExternalAddresses) => {
+            println!("Waiting for a confirmed external address before registering with the rendezvous point");
+        }
+        Err(e) => eprintln!("Failed to register with rendezvous point {point}: {e}"),
+    }
+}
+
+pub fn rendezvous_unregister(swarm: &mut Swarm<ChatBehaviour>, state: &ChatState, topic: &str) {
+    if let (Some(point), Ok(namespace)) = (state.rendezvous_point, rendezvous_namespace(topic)) {
+        swarm.behaviour_mut().rendezvous.unregister(namespace, point);
+    }
+}
+
+pub fn rendezvous_discover(swarm: &mut Swarm<ChatBehaviour>, state: &ChatState) {
+    let Some(point) = state.rendezvous_point else {
+        return;
+    };
+    if !swarm.is_connected(&point) {
+        return;
+    }
+    if let Some(Ok(namespace)) = current_topic(swarm).map(|topic| rendezvous_namespace(&topic)) {
+        let cookie = state.rendezvous_cookie.clone();
+        swarm.behaviour_mut().rendezvous.discover(Some(namespace), cookie, None, point);
+    }
+}
@@ -0,0 +1,225 @@
+use clap::ValueEnum;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use super::shares;
+
+// What to do when a finished download has the same name as a file we already have.
+// Whatever the policy, a download identical to the existing file is just dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    // save it as "name (1).ext"
+    Rename,
+    // replace the existing file
+    Overwrite,
+    // keep the existing file and throw the download away
+    Skip,
+    // hold on to the download until the user picks one of the above with /keep
+    Ask,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Layout {
+    // everything straight into the download directory
+    Flat,
+    // a folder for each peer, named after its peer id
+    PerPeer,
+}
+
+pub struct DownloadOptions {
+    pub dir: PathBuf,
+    pub layout: Layout,
+    pub collision: CollisionPolicy,
+}
+
+impl DownloadOptions {
+    // Where a file from a peer should end up, before any collision handling
+    pub fn destination(&self, peer: &PeerId, filename: &str) -> PathBuf {
+        self.base(peer).join(saved_name(filename))
+    }
+
+    // Where a file from a folder download ends up, keeping its place in the folder.
+    // `relative` must already have been checked with manifest::safe_path
+    pub fn destination_in(&self, peer: &PeerId, relative: &Path) -> PathBuf {
+        self.base(peer).join(relative)
+    }
+
+    // The folder files from a peer are saved in
+    pub fn base(&self, peer: &PeerId) -> PathBuf {
+        match self.layout {
+            Layout::Flat => self.dir.clone(),
+            Layout::PerPeer => self.dir.join(peer.to_string()),
+        }
+    }
+}
+
+// The name a file asked for by name is saved under, a file in a peer's shared folder is saved
+// flat, e.g. music/song.mp3 as music_song.mp3
+pub fn saved_name(filename: &str) -> String {
+    // clean the name to stop attacks, saw this on some examples dont really know what it means
+    filename.replace(&['/', '\\'][..], "_")
+}
+
+// Downloads are written to a temporary file next to the destination and only renamed into
+// place once complete, so a half finished download never looks like the real thing
+pub fn part_path(destination: &Path, id: u64) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.part", id));
+    destination.with_file_name(name)
+}
+
+pub enum Finished {
+    Saved(PathBuf),
+    // the same file was already there
+    Identical(PathBuf),
+    // the existing file was kept, and the download thrown away
+    Skipped(PathBuf),
+    // a file is in the way and the policy is to ask
+    Conflict(PathBuf),
+}
+
+impl fmt::Display for Finished {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finished::Saved(path) => write!(f, "saved to {:?}", path),
+            Finished::Identical(path) => write!(f, "identical to {:?}, not keeping a second copy", path),
+            Finished::Skipped(path) => write!(f, "{:?} already exists, kept it and discarded the download", path),
+            Finished::Conflict(path) => write!(f, "{:?} already exists", path),
+        }
+    }
+}
+
+// A completed download waiting to be checked and moved into place
+pub struct FinishJob {
+    pub id: u64,
+    pub kept: bool,
+    pub elapsed: Duration,
+    pub part: PathBuf,
+    pub destination: PathBuf,
+    pub policy: CollisionPolicy,
+    pub hash: Option<String>,
+}
+
+// A completed download that has been checked and moved into place on a blocking thread
+pub struct Finishing {
+    pub id: u64,
+    // it had stopped at a conflict, and this is the choice made with /keep
+    pub kept: bool,
+    // how long the download itself took, the checking isn't counted
+    pub elapsed: Duration,
+    pub result: Result<Finished, String>,
+}
+
+// Checks a completed download is the file asked for, when it was asked for by hash, then moves it into place.
+// Both mean reading the whole file, so this is run with spawn_blocking rather than in the event loop
+pub fn check_and_finish(part: &Path, destination: &Path, policy: CollisionPolicy, hash: Option<&str>) -> Result<Finished, String> {
+    if let Some(hash) = hash {
+        let matches = hash_file(part).map(|actual| shares::hex(&actual) == hash);
+        if !matches.as_ref().is_ok_and(|m| *m) {
+            let _ = std::fs::remove_file(part);
+            return Err(match matches {
+                Err(e) => format!("could not check the file: {}", e),
+                Ok(_) => "the file sent doesn't match its hash, it has been thrown away".to_string(),
+            });
+        }
+    }
+    finish(part, destination, policy).map_err(|e| format!("could not move it to {:?}: {}", destination, e))
+}
+
+// Finishes downloads one at a time. finish looks for a free name and then renames into it, two
+// downloads with the same name finishing together would both pick the same name otherwise
+pub fn finish_worker(mut jobs: UnboundedReceiver<FinishJob>, results: UnboundedSender<Finishing>) {
+    while let Some(job) = jobs.blocking_recv() {
+        let result = check_and_finish(&job.part, &job.destination, job.policy, job.hash.as_deref());
+        let finishing = Finishing { id: job.id, kept: job.kept, elapsed: job.elapsed, result };
+        if results.send(finishing).is_err() {
+            return;
+        }
+    }
+}
+
+// Moves a completed download into place, dealing with any file that is already there
+pub fn finish(part: &Path, destination: &Path, policy: CollisionPolicy) -> std::io::Result<Finished> {
+    if !destination.try_exists()? {
+        std::fs::rename(part, destination)?;
+        return Ok(Finished::Saved(destination.to_path_buf()));
+    }
+    if let Some(existing) = find_identical(part, destination)? {
+        std::fs::remove_file(part)?;
+        return Ok(Finished::Identical(existing));
+    }
+    match policy {
+        CollisionPolicy::Overwrite => {
+            std::fs::rename(part, destination)?;
+            Ok(Finished::Saved(destination.to_path_buf()))
+        }
+        CollisionPolicy::Rename => {
+            let free = free_name(destination)?;
+            std::fs::rename(part, &free)?;
+            Ok(Finished::Saved(free))
+        }
+        CollisionPolicy::Skip => {
+            std::fs::remove_file(part)?;
+            Ok(Finished::Skipped(destination.to_path_buf()))
+        }
+        CollisionPolicy::Ask => Ok(Finished::Conflict(destination.to_path_buf())),
+    }
+}
+
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    path.with_file_name(format!("{} ({}){}", stem, n, extension))
+}
+
+// Finds "name (1).ext", "name (2).ext" and so on, whichever is free first
+fn free_name(path: &Path) -> std::io::Result<PathBuf> {
+    for n in 1.. {
+        let candidate = numbered(path, n);
+        if !candidate.try_exists()? {
+            return Ok(candidate);
+        }
+    }
+    unreachable!("ran out of numbers for {:?}", path)
+}
+
+// Looks through the file and any renamed copies of it for one with the same contents as the download
+fn find_identical(part: &Path, destination: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut candidate = destination.to_path_buf();
+    for n in 1.. {
+        if !candidate.try_exists()? {
+            break;
+        }
+        if same_contents(part, &candidate)? {
+            return Ok(Some(candidate));
+        }
+        candidate = numbered(destination, n);
+    }
+    Ok(None)
+}
+
+fn same_contents(a: &Path, b: &Path) -> std::io::Result<bool> {
+    if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(hash_file(a)? == hash_file(b)?)
+}
+
+pub fn hash_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
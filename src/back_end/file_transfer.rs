@@ -1,81 +1,523 @@
 use serde::{Deserialize, Serialize};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use std::path::Path;
-use libp2p::{request_response, swarm::NetworkBehaviour, PeerId};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use libp2p::request_response::{InboundRequestId, OutboundRequestId, ResponseChannel};
+use libp2p::{swarm::NetworkBehaviour, PeerId};
+
+use super::state::ChatState;
+use super::throttle::{self, Direction};
+use super::progress::{TransferEvent, TransferEventKind};
+use super::compression;
+use super::downloads::{self, Finished};
+use super::links::SwapLink;
+use super::manifest::{self, ManifestRequest, ManifestResponse};
+use super::preview::{PreviewRequest, PreviewResponse};
+use super::transfers::{Download, Group, GroupState, Priority, TransferState};
+
+// Files are sent in chunks, one request per chunk, so transfers can be throttled
+// and a big file never has to sit in memory all at once
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+// How long to wait for a chunk. The sender may hold it back for up to throttle::MAX_DELAY, this leaves
+// plenty of room on top of that for the chunk to actually get here
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(throttle::MAX_DELAY.as_secs() + 40);
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct FileRequest(pub String);
+pub struct FileRequest {
+    pub filename: String,
+    // where in the file the requested chunk starts
+    pub offset: u64,
+    // the compression the requester can unpack, the chunk may come back using one of them
+    #[serde(default)]
+    pub compression: Vec<String>,
+    // asks for the file with this sha256 rather than by name, the name is then only for show
+    #[serde(default)]
+    pub hash: Option<String>,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileResponse {
     pub filename: String, // To store the name of the file
-    pub data: Vec<u8>,    // To store the actual file content
+    pub offset: u64,
+    pub total_size: u64,  // size of the whole file, so the requester knows when it has it all
+    pub data: Vec<u8>,    // To store the chunk of the file content
+    // Set when the file wasn't sent, e.g. because it doesn't exist or the peer is busy
+    #[serde(default)]
+    pub error: Option<String>,
+    // how `data` was compressed, if it was
+    #[serde(default)]
+    pub compression: Option<String>,
+}
+
+impl FileResponse {
+    fn error(request: &FileRequest, error: String) -> Self {
+        FileResponse {
+            filename: request.filename.clone(),
+            offset: request.offset,
+            total_size: 0,
+            data: Vec::new(),
+            error: Some(error),
+            compression: None,
+        }
+    }
+}
+
+// A response or a request for the next chunk, held back to keep within the rate limits
+pub enum DelayedTransfer {
+    Response {
+        channel: ResponseChannel<FileResponse>,
+        response: FileResponse,
+    },
+    NextChunk(u64),
 }
 
 #[derive(NetworkBehaviour)]
 pub struct FileTransferBehaviour {
-    pub request_response: libp2p::request_response::cbor::Behaviour<FileRequest, FileResponse>
+    pub request_response: libp2p::request_response::cbor::Behaviour<FileRequest, FileResponse>,
+    // lists the files in a folder, or a set of files, before they are downloaded one by one
+    pub manifest: libp2p::request_response::cbor::Behaviour<ManifestRequest, ManifestResponse>,
+    // a look at a file before downloading it
+    pub preview: libp2p::request_response::cbor::Behaviour<PreviewRequest, PreviewResponse>,
 }
 impl FileTransferBehaviour {
-    pub fn send_request(
-        &mut self,
-        peer_id: PeerId,
-        request: FileRequest,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Send a request to the peer using the `request_response` protocol
-        self.request_response.send_request(&peer_id, request);
-        Ok(())
+    // Queues a download of a file from a peer, into the download directory
+    pub fn queue_download(&mut self, peer_id: PeerId, filename: String, priority: Priority, state: &mut ChatState) -> u64 {
+        let id = state.transfers.enqueue(peer_id, filename, priority);
+        self.start_queued(state);
+        id
+    }
+
+    // Queues a download of a file by its hash, from the peer a swap link points at
+    pub fn queue_link(&mut self, peer_id: PeerId, link: &SwapLink, priority: Priority, state: &mut ChatState) -> u64 {
+        let id = state.transfers.enqueue_link(peer_id, link, priority);
+        self.start_queued(state);
+        id
+    }
+
+    pub fn handle_preview_request(&mut self, request: PreviewRequest, channel: ResponseChannel<PreviewResponse>, state: &ChatState) {
+        let response = PreviewResponse::build(&request, &state.shares);
+        let _ = self.preview.send_response(channel, response);
+    }
+
+    // Asks a peer which files are under some of their files and folders, they are queued
+    // as one transfer once the list comes back
+    pub fn queue_group(&mut self, peer_id: PeerId, paths: Vec<String>, priority: Priority, state: &mut ChatState) -> u64 {
+        let id = state.transfers.add_group(peer_id, paths.join(", "), priority);
+        let request_id = self.manifest.send_request(&peer_id, ManifestRequest { paths });
+        state.transfers.track_manifest(request_id, id);
+        id
+    }
+
+    pub fn handle_manifest_request(&mut self, request: ManifestRequest, channel: ResponseChannel<ManifestResponse>, state: &ChatState) {
+        let response = ManifestResponse::build(&request, &state.shares);
+        if let Some(error) = &response.error {
+            println!("Turned down request for {}: {}", request.paths.join(", "), error);
+        }
+        let _ = self.manifest.send_response(channel, response);
+    }
+
+    // Queues every file in the list. The paths come from the other peer, so if any of them
+    // would end up outside the download folder none of them are downloaded
+    pub fn handle_manifest(&mut self, request_id: OutboundRequestId, response: ManifestResponse, state: &mut ChatState) {
+        let Some(id) = state.transfers.finish_manifest(&request_id) else {
+            return;
+        };
+        let Some(group) = state.transfers.group(id) else {
+            return;
+        };
+        if group.state != GroupState::Listing {
+            return;
+        }
+        let entries: Option<Vec<_>> = response.entries
+            .iter()
+            .map(|entry| manifest::safe_path(&entry.path).map(|relative| (entry, relative)))
+            .collect();
+        let failed = match (response.error, entries) {
+            (Some(error), _) => Some(format!("{} did not send it: {}", group.peer, error)),
+            (None, None) => Some(format!("{} sent a file list with unsafe paths", group.peer)),
+            (None, Some(entries)) if entries.is_empty() => Some("there are no files to download".to_string()),
+            (None, Some(entries)) if entries.len() > manifest::MAX_ENTRIES => Some("the file list is too long".to_string()),
+            (None, Some(entries)) => {
+                for (entry, relative) in entries {
+                    state.transfers.enqueue_in_group(id, entry.path.clone(), &relative, entry.size);
+                }
+                None
+            }
+        };
+        if let Some(reason) = failed {
+            self.fail_group(id, reason, state);
+            return;
+        }
+        if let Some(group) = state.transfers.group(id) {
+            state.transfer_events.push(group_event(id, group, TransferEventKind::Started));
+        }
+        self.start_queued(state);
+    }
+
+    pub fn handle_manifest_failure(&mut self, request_id: OutboundRequestId, error: String, state: &mut ChatState) {
+        if let Some(id) = state.transfers.finish_manifest(&request_id) {
+            self.fail_group(id, error, state);
+        }
+    }
+
+    fn fail_group(&mut self, id: u64, reason: String, state: &mut ChatState) {
+        let Some(group) = state.transfers.group_mut(id) else {
+            return;
+        };
+        if group.state == GroupState::Listing {
+            state.transfer_events.push(group_event(id, group, TransferEventKind::Failed(reason.clone())));
+            group.state = GroupState::Failed(reason);
+        }
+    }
+
+    // Starts as many queued downloads as the parallelism limit allows
+    pub fn start_queued(&mut self, state: &mut ChatState) {
+        for id in state.transfers.start_queued() {
+            self.report(id, TransferEventKind::Started, state);
+            self.request_chunk(id, state);
+        }
+    }
+
+    // Files that are part of a group are reported as the group, apart from ones that fail
+    fn report(&mut self, id: u64, kind: TransferEventKind, state: &mut ChatState) {
+        let Some(download) = state.transfers.get(id) else {
+            return;
+        };
+        if download.group.is_none() || matches!(kind, TransferEventKind::Failed(_)) {
+            state.transfer_events.push(download_event(id, download, kind));
+        }
+    }
+
+    // A download has stopped for good, which may finish its group and lets the next one in the queue start
+    fn finished(&mut self, id: u64, state: &mut ChatState) {
+        if let Some((group_id, failed)) = state.transfers.finish_group(id) {
+            if let Some(group) = state.transfers.group(group_id) {
+                let kind = match failed {
+                    0 => TransferEventKind::Completed {
+                        total: group.total,
+                        elapsed: group.meter.elapsed(),
+                        path: Some(state.transfers.options().base(&group.peer)),
+                    },
+                    failed => TransferEventKind::Failed(format!("{} of {} files did not download", failed, group.files.len())),
+                };
+                state.transfer_events.push(group_event(group_id, group, kind));
+            }
+        }
+        self.start_queued(state);
+    }
 
-        
+    fn fail(&mut self, id: u64, reason: String, state: &mut ChatState) {
+        if let Some(download) = state.transfers.get_mut(id) {
+            download.state = TransferState::Failed(reason.clone());
+        }
+        self.report(id, TransferEventKind::Failed(reason), state);
+        self.finished(id, state);
     }
+
+    fn request_chunk(&mut self, id: u64, state: &mut ChatState) {
+        let Some(download) = state.transfers.get(id) else {
+            return;
+        };
+        let request = FileRequest {
+            filename: download.filename.clone(),
+            offset: download.received,
+            compression: compression::supported(state.compression),
+            hash: download.hash.clone(),
+        };
+        let request_id = self.request_response.send_request(&download.peer, request);
+        state.transfers.track(request_id, id);
+    }
+
     pub async fn handle_request(
         &mut self,
+        peer: PeerId,
         request: FileRequest,
-        channel: request_response::ResponseChannel<FileResponse>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let filename = request.0.clone();
-        println!("Received request for file: {}", filename);
-
-        let file_bytes = self.select_file(filename).await?;
-        let file_response = FileResponse {
-            filename: request.0.clone(),
-            data: file_bytes,
-        };
-        self.request_response.send_response(channel, file_response).unwrap();
-        Ok(())
-    }
-
-    async fn select_file(&self, filename: String) -> Result<Vec<u8>, std::io::Error> {
-        // if file doesnt exist, return emply vec, therefore can say on reciving end that file doesnt exist
-        // There has got to be a better way, but the only way a request doesnt crash is if the file exists
-        let mut file_bytes = Vec::new();
-
-        let path = Path::new("uploads").join(&filename);
-        println!("Reading file: {:?}", path);
-
-        if !path.exists() {
-            eprintln!("Warning: File does not exist - {:?}", path);
-            return Ok(file_bytes);
-        } else if !path.is_file() {
-            eprintln!("Warning: Path is not a file - {:?}", path);
-            return Ok(file_bytes);
-        }
-
-        // Attempt to open the file
-        let mut file = match File::open(&path).await {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Warning: Error opening file: {:?} - {}", path, e);
-                return Ok(file_bytes);
+        request_id: InboundRequestId,
+        channel: ResponseChannel<FileResponse>,
+        state: &mut ChatState,
+    ) {
+        // a request by hash is for whichever of our files has those contents
+        let (name, path) = match &request.hash {
+            Some(hash) => match state.shares.find_hash(hash) {
+                Some((name, file)) => (name.clone(), Some(file.path(name))),
+                None => (request.filename.clone(), None),
+            },
+            None => (request.filename.clone(), state.shares.resolve(&request.filename)),
+        };
+        // under an upload limit the chunk is cut down so it doesn't have to be held back too long
+        let len = state.throttle.chunk_size(Direction::Up, peer, CHUNK_SIZE);
+        let mut response = read_chunk(&request, path, len).await;
+        if response.error.is_none() {
+            let len = response.data.len() as u64;
+            match state.upload_slots.acquire(peer, &name, request_id, request.offset, len, response.total_size) {
+                Ok(started) => state.transfer_events.extend(started),
+                Err(reason) => {
+                    println!("Turned down request for {}: {}", name, reason);
+                    response = FileResponse::error(&request, reason);
+                }
+            }
+        }
+        if response.error.is_none() {
+            let raw = response.data.len() as u64;
+            let codec = compression::choose(&request.compression, &name, state.compression);
+            if let Some((codec, packed)) = codec.and_then(|codec| Some((codec, compression::compress(codec, &response.data)?))) {
+                response.data = packed;
+                response.compression = Some(codec.name().to_string());
+            }
+            state.compression_stats.sent += raw;
+            state.compression_stats.sent_wire += response.data.len() as u64;
+        }
+        let delay = match response.error {
+            None => state.throttle.reserve(Direction::Up, peer, response.data.len() as u64),
+            Some(_) => std::time::Duration::ZERO,
+        };
+        if delay.is_zero() {
+            let _ = self.request_response.send_response(channel, response);
+        } else {
+            state.delayed_transfers.push(delay, DelayedTransfer::Response { channel, response });
+        }
+    }
+
+    // Saves a chunk we asked for, then asks for the next one once the download limit allows
+    pub async fn handle_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        response: FileResponse,
+        state: &mut ChatState,
+    ) {
+        let Some(id) = state.transfers.finish_request(&request_id) else {
+            return;
+        };
+        let Some(download) = state.transfers.get_mut(id) else {
+            return;
+        };
+        if download.state == TransferState::Cancelled {
+            return;
+        }
+        let wire = response.data.len() as u64;
+        let data = match &response.compression {
+            Some(codec) => compression::decompress(codec, &response.data, CHUNK_SIZE as usize),
+            None => Ok(response.data),
+        };
+        let failed = match (response.error, response.offset == download.received, data) {
+            (Some(error), _, _) => Err(format!("{} did not send it: {}", download.peer, error)),
+            (None, false, _) => Err(format!("{} sent the wrong part of the file", download.peer)),
+            (None, true, Err(e)) => Err(format!("{} sent a chunk that could not be unpacked: {}", download.peer, e)),
+            (None, true, Ok(data)) => match write_chunk(&download.part, response.offset, &data).await {
+                Err(e) => Err(format!("could not write to {:?}: {}", download.part, e)),
+                Ok(()) => Ok(data.len() as u64),
+            },
+        };
+        let len = match failed {
+            Ok(len) => len,
+            Err(reason) => {
+                self.fail(id, reason, state);
+                return;
             }
         };
+        state.compression_stats.received += len;
+        state.compression_stats.received_wire += wire;
+        let Some(download) = state.transfers.get_mut(id) else {
+            return;
+        };
+        download.received += len;
+        download.total = Some(response.total_size);
+        let complete = download.received >= response.total_size;
+        let progress = match complete {
+            false => download.meter.progress(download.received, response.total_size),
+            true => None,
+        };
+        let (peer, group) = (download.peer, download.group);
+        if let Some(kind) = progress {
+            self.report(id, kind, state);
+        }
+        // a group's progress is everything received for any of its files
+        if let Some(group_id) = group {
+            if let Some(group) = state.transfers.group_mut(group_id) {
+                group.received += len;
+                if group.received < group.total {
+                    if let Some(kind) = group.meter.progress(group.received, group.total) {
+                        state.transfer_events.push(group_event(group_id, group, kind));
+                    }
+                }
+            }
+        }
+        let policy = state.transfers.options().collision;
+        let Some(download) = state.transfers.get_mut(id) else {
+            return;
+        };
+        if complete {
+            // checking the hash and moving the file can take a while for a big file, handle_finished
+            // carries on once that is done. The download no longer needs its slot in the meantime
+            let elapsed = download.meter.elapsed();
+            state.transfers.finish_in_background(id, policy, false, elapsed);
+            self.start_queued(state);
+            return;
+        }
+        if len == 0 {
+            self.fail(id, "peer stopped sending part way through".to_string(), state);
+            return;
+        }
+        // a paused download keeps what it has, and picks up from there when resumed
+        if download.state != TransferState::Active {
+            return;
+        }
+        download.busy = true;
+        // the limit is on what comes over the network, so compressed chunks count for less
+        let delay = state.throttle.reserve(Direction::Down, peer, wire);
+        if delay.is_zero() {
+            self.request_chunk(id, state);
+        } else {
+            state.delayed_transfers.push(delay, DelayedTransfer::NextChunk(id));
+        }
+    }
 
-        if let Err(e) = file.read_to_end(&mut file_bytes).await {
+    // A completed download has been checked and moved into place, or not
+    pub fn handle_finished(&mut self, finishing: downloads::Finishing, state: &mut ChatState) {
+        let id = finishing.id;
+        let Some(download) = state.transfers.get_mut(id) else {
+            return;
+        };
+        if finishing.kept {
+            match finishing.result {
+                Ok(finished) => {
+                    println!("Transfer #{} {}", id, finished);
+                    download.state = TransferState::Completed;
+                }
+                Err(e) => {
+                    eprintln!("Failed to move transfer #{} into place: {}", id, e);
+                    download.state = TransferState::Conflict(download.destination.clone());
+                }
+            }
+            return;
+        }
+        let total = download.total.unwrap_or(download.received);
+        let elapsed = finishing.elapsed;
+        let kind = match finishing.result {
+            Ok(Finished::Saved(path)) => {
+                download.state = TransferState::Completed;
+                TransferEventKind::Completed { total, elapsed, path: Some(path) }
+            }
+            Ok(Finished::Conflict(path)) => {
+                println!("Transfer #{} finished, but {:?} already exists. Use /keep {} rename, overwrite or skip", id, path, id);
+                download.state = TransferState::Conflict(path);
+                self.finished(id, state);
+                return;
+            }
+            Ok(finished) => {
+                println!("Transfer #{} {}", id, finished);
+                download.state = TransferState::Completed;
+                TransferEventKind::Completed { total, elapsed, path: None }
+            }
+            Err(reason) => {
+                self.fail(id, reason, state);
+                return;
+            }
+        };
+        self.report(id, kind, state);
+        self.finished(id, state);
+    }
+
+    pub fn handle_failure(&mut self, request_id: OutboundRequestId, error: String, state: &mut ChatState) {
+        let Some(id) = state.transfers.finish_request(&request_id) else {
+            return;
+        };
+        match state.transfers.get(id) {
+            Some(download) if download.state == TransferState::Active || download.state == TransferState::Queued => {
+                self.fail(id, error, state);
+            }
+            _ => self.start_queued(state),
+        }
+    }
+
+    // Sends whatever the throttle was holding back that is now due
+    pub fn send_delayed(&mut self, state: &mut ChatState) {
+        for transfer in state.delayed_transfers.pop_due() {
+            match transfer {
+                DelayedTransfer::Response { channel, response } => {
+                    let _ = self.request_response.send_response(channel, response);
+                }
+                DelayedTransfer::NextChunk(id) => {
+                    match state.transfers.get_mut(id) {
+                        Some(download) if download.state == TransferState::Active => self.request_chunk(id, state),
+                        // paused or cancelled while it was waiting
+                        Some(download) => download.busy = false,
+                        None => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn download_event(id: u64, download: &Download, kind: TransferEventKind) -> TransferEvent {
+    TransferEvent {
+        id: Some(id),
+        direction: Direction::Down,
+        peer: download.peer,
+        filename: download.filename.clone(),
+        kind,
+    }
+}
+
+fn group_event(id: u64, group: &Group, kind: TransferEventKind) -> TransferEvent {
+    TransferEvent {
+        id: Some(id),
+        direction: Direction::Down,
+        peer: group.peer,
+        filename: group.name.clone(),
+        kind,
+    }
+}
+
+// Reads the requested chunk of a shared file. A file that isn't in the share index gets an
+// error response, so the requester knows what went wrong
+async fn read_chunk(request: &FileRequest, path: Option<PathBuf>, len: u64) -> FileResponse {
+    let Some(path) = path else {
+        eprintln!("Warning: File is not shared - {}", request.filename);
+        return FileResponse::error(request, "file does not exist".to_string());
+    };
+
+    let read = async {
+        let mut file = File::open(&path).await?;
+        let total_size = file.metadata().await?.len();
+        file.seek(SeekFrom::Start(request.offset)).await?;
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data).await?;
+        Ok::<_, std::io::Error>((data, total_size))
+    };
+    match read.await {
+        Ok((data, total_size)) => FileResponse {
+            filename: request.filename.clone(),
+            offset: request.offset,
+            total_size,
+            data,
+            error: None,
+            compression: None,
+        },
+        Err(e) => {
             eprintln!("Warning: Error reading file: {:?} - {}", path, e);
-            return Ok(file_bytes);
+            FileResponse::error(request, "could not read file".to_string())
         }
+    }
+}
 
-        // Return the file bytes if successful
-        Ok(file_bytes)
+async fn write_chunk(path: &Path, offset: u64, data: &[u8]) -> std::io::Result<()> {
+    // create the downloads directory if it doesn't exist
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
     }
-}
\ No newline at end of file
+    // the first chunk starts the file again from scratch
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(offset == 0)
+        .open(path)
+        .await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    // tokio finishes writes in the background, make sure the chunk is on disk before the file is checked or moved
+    file.flush().await
+}
@@ -0,0 +1,155 @@
+use libp2p::gossipsub::{self, MessageAcceptance, PeerScoreParams, PeerScoreThresholds, TopicScoreParams};
+use libp2p::PeerId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::utils::ALLOWED_TOPICS;
+use super::wishlist::{self, WishlistMessage};
+
+// Largest chat message we publish or accept, including the "[topic]: " prefix
+pub const MAX_MESSAGE_SIZE: usize = 2048;
+// Each author may send this many messages per window, anything over that is dropped. It only counts against the
+// sender's score when the author sent it to us directly, a relay forwarding a flooder's messages has done nothing wrong
+const RATE_LIMIT_MESSAGES: usize = 10;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+const MAX_TRACKED_AUTHORS: usize = 256;
+
+// Messages are held back until validate() has looked at them, so spam is never forwarded on to the rest of the mesh.
+// The transmit size is left at the default, it covers whole RPC frames (IHAVE lists, batched forwards) and not just
+// one message. The size of each message is limited in check() instead
+pub fn config() -> Result<gossipsub::Config, gossipsub::ConfigBuilderError> {
+    gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .validate_messages()
+        .build()
+}
+
+pub fn peer_score_params() -> PeerScoreParams {
+    let mut params = PeerScoreParams::default();
+    for topic in ALLOWED_TOPICS.into_iter().chain([wishlist::TOPIC]) {
+        params.topics.insert(gossipsub::IdentTopic::new(topic).hash(), topic_score_params());
+    }
+    params
+}
+
+// Chat rooms are quiet, so peers are not penalised for delivering few messages (P3). What counts is
+// sending us messages that fail validation (P4), which pushes a flooder under the graylist threshold
+// after a handful of rejections, and it is forgotten again after a few minutes of good behaviour.
+fn topic_score_params() -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight: 1.0,
+        time_in_mesh_weight: 0.01,
+        time_in_mesh_quantum: Duration::from_secs(1),
+        time_in_mesh_cap: 3600.0,
+        first_message_deliveries_weight: 1.0,
+        first_message_deliveries_decay: 0.5,
+        first_message_deliveries_cap: 50.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        invalid_message_deliveries_weight: -10.0,
+        invalid_message_deliveries_decay: 0.9,
+        ..TopicScoreParams::default()
+    }
+}
+
+pub fn peer_score_thresholds() -> PeerScoreThresholds {
+    PeerScoreThresholds::default()
+}
+
+// A message that passed validation, by the topic it came in on
+pub enum Received {
+    Chat(String),
+    Wishlist(WishlistMessage),
+}
+
+pub fn check(topic: &gossipsub::TopicHash, data: &[u8]) -> Result<Received, &'static str> {
+    if topic.as_str() == wishlist::TOPIC {
+        wishlist::check_message(data).map(Received::Wishlist)
+    } else {
+        check_message(topic, data).map(Received::Chat)
+    }
+}
+
+// Checks a chat message is something our own client could have sent: small enough, valid UTF-8,
+// "[topic]: text" for the topic it arrived on, and no control characters that could mess with the terminal
+pub fn check_message(topic: &gossipsub::TopicHash, data: &[u8]) -> Result<String, &'static str> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err("message too large");
+    }
+    let text = std::str::from_utf8(data).map_err(|_| "message is not valid UTF-8")?;
+    let body = text
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_prefix(topic.as_str()))
+        .and_then(|rest| rest.strip_prefix("]: "))
+        .ok_or("message is not for the topic it was published on")?;
+    if body.trim().is_empty() {
+        return Err("message is empty");
+    }
+    if text.chars().any(char::is_control) {
+        return Err("message contains control characters");
+    }
+    Ok(text.to_string())
+}
+
+// Tracks how often each author has posted recently
+pub struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    recent: HashMap<PeerId, VecDeque<Instant>>,
+}
+
+// The chat limit
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW)
+    }
+}
+
+pub enum RateLimit {
+    Allowed,
+    // the first message over the limit in this window, worth telling the user about
+    Exceeded,
+    // still over the limit, already reported
+    StillExceeded,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter { limit, window, recent: HashMap::new() }
+    }
+
+    pub fn check(&mut self, author: PeerId) -> RateLimit {
+        if self.recent.len() > MAX_TRACKED_AUTHORS {
+            self.prune();
+        }
+        let now = Instant::now();
+        let recent = self.recent.entry(author).or_default();
+        while recent.front().is_some_and(|at| now.duration_since(*at) > self.window) {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+        match recent.len() {
+            n if n <= self.limit => RateLimit::Allowed,
+            n if n == self.limit + 1 => RateLimit::Exceeded,
+            _ => RateLimit::StillExceeded,
+        }
+    }
+
+    // Forgets authors that haven't posted within the window, so the map doesn't grow forever
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.recent.retain(|_, recent| recent.back().is_some_and(|at| now.duration_since(*at) <= self.window));
+    }
+}
+
+// Reports the outcome of validation, accepted messages are forwarded on and rejected ones lower the sender's score
+pub fn report(
+    gossipsub: &mut gossipsub::Behaviour,
+    message_id: &gossipsub::MessageId,
+    propagation_source: &PeerId,
+    acceptance: MessageAcceptance,
+) {
+    if let Err(e) = gossipsub.report_message_validation_result(message_id, propagation_source, acceptance) {
+        eprintln!("Failed to report message validation result: {e:?}");
+    }
+}
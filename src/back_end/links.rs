@@ -0,0 +1,140 @@
+use libp2p::PeerId;
+use std::fmt;
+use std::str::FromStr;
+
+use super::manifest;
+
+const SCHEME: &str = "swap://";
+
+// A link to a file by its contents, that can be pasted into chat and fetched with /get.
+// Looks like swap://<sha256>/<size>/<peer_id>/<name>, the name is percent encoded so the
+// link has no spaces in it. The peer is only a hint about who has the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapLink {
+    pub hash: String,
+    pub size: u64,
+    pub provider: Option<PeerId>,
+    pub name: String,
+}
+
+impl fmt::Display for SwapLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let provider = self.provider.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string());
+        write!(f, "{}{}/{}/{}/{}", SCHEME, self.hash, self.size, provider, encode(&self.name))
+    }
+}
+
+impl FromStr for SwapLink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.trim_matches('"').strip_prefix(SCHEME).ok_or("swap links start with swap://")?;
+        let mut parts = rest.splitn(4, '/');
+        let (Some(hash), Some(size), Some(provider), Some(name)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err("the link is missing parts, it should be swap://<hash>/<size>/<peer_id>/<name>".to_string());
+        };
+        if !is_hash(hash) {
+            return Err(format!("{} is not a sha256 hash", hash));
+        }
+        let size = size.parse().map_err(|_| format!("{} is not a file size", size))?;
+        let provider = match provider {
+            "-" => None,
+            provider => Some(PeerId::from_str(provider).map_err(|e| format!("invalid peer ID {}: {}", provider, e))?),
+        };
+        let name = decode(name).ok_or("the file name in the link is not valid")?;
+        // the name becomes the download's file name, so it can't be a path or something like ..
+        if !is_file_name(&name) {
+            return Err(format!("{:?} is not a valid file name", name));
+        }
+        Ok(SwapLink { hash: hash.to_ascii_lowercase(), size, provider, name })
+    }
+}
+
+// 64 hex digits, the way hashes are shown in /shares and links
+pub fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// A single plain file name, safe to join onto the download folder
+pub fn is_file_name(name: &str) -> bool {
+    !name.contains('/') && manifest::safe_path(name).is_some()
+}
+
+fn encode(name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut chars = encoded.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "cded5439a6873de2da1d3930c65939bdc20976f0deb96cd2032fc3a2132bcdc3";
+
+    fn link(name: &str) -> String {
+        format!("swap://{}/11/-/{}", HASH, name)
+    }
+
+    #[test]
+    fn link_round_trips() {
+        let link = SwapLink { hash: HASH.to_string(), size: 11, provider: Some(PeerId::random()), name: "my notes (1).txt".to_string() };
+        assert_eq!(SwapLink::from_str(&link.to_string()), Ok(link));
+    }
+
+    #[test]
+    fn link_without_provider() {
+        let parsed = SwapLink::from_str(&link("notes.txt")).unwrap();
+        assert_eq!(parsed.provider, None);
+        assert_eq!(parsed.name, "notes.txt");
+    }
+
+    #[test]
+    fn malformed_links_are_rejected() {
+        assert!(SwapLink::from_str("http://example.com/notes.txt").is_err());
+        assert!(SwapLink::from_str(&format!("swap://{}/11", HASH)).is_err());
+        assert!(SwapLink::from_str("swap://abc/11/-/notes.txt").is_err());
+        assert!(SwapLink::from_str(&format!("swap://{}/big/-/notes.txt", HASH)).is_err());
+        assert!(SwapLink::from_str(&format!("swap://{}/11/not-a-peer/notes.txt", HASH)).is_err());
+        assert!(SwapLink::from_str(&link("bad%zz")).is_err());
+        assert!(SwapLink::from_str(&link("%FF")).is_err());
+    }
+
+    #[test]
+    fn link_names_must_be_plain_file_names() {
+        assert!(SwapLink::from_str(&link("..")).is_err());
+        assert!(SwapLink::from_str(&link("%2E%2E")).is_err());
+        assert!(SwapLink::from_str(&link("photos/beach.jpg")).is_err());
+        assert!(SwapLink::from_str(&link("photos%2Fbeach.jpg")).is_err());
+        assert!(SwapLink::from_str(&link("%2Fetc%2Fpasswd")).is_err());
+        assert!(SwapLink::from_str(&link("")).is_err());
+    }
+
+    #[test]
+    fn is_file_name_only_allows_one_component() {
+        assert!(is_file_name("notes.txt"));
+        assert!(!is_file_name("."));
+        assert!(!is_file_name(".."));
+        assert!(!is_file_name("a/b"));
+        assert!(!is_file_name("/notes.txt"));
+    }
+}
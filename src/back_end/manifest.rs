@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use super::shares::ShareIndex;
+
+// A single manifest can't list more files than this, so a huge folder can't be used to tie us up
+pub const MAX_ENTRIES: usize = 10_000;
+
+// Asks a peer for the files under some of their shared files and folders
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRequest {
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    // relative to the uploads folder, with / between folders, this is what gets requested
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestResponse {
+    pub entries: Vec<ManifestEntry>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ManifestResponse {
+    pub fn build(request: &ManifestRequest, shares: &ShareIndex) -> Self {
+        match list_files(shares, &request.paths) {
+            Ok(entries) => ManifestResponse { entries, error: None },
+            Err(error) => ManifestResponse { entries: Vec::new(), error: Some(error) },
+        }
+    }
+}
+
+// Lists every shared file under the requested paths
+fn list_files(shares: &ShareIndex, paths: &[String]) -> Result<Vec<ManifestEntry>, String> {
+    // a file asked for twice, or inside a folder that was also asked for, is only listed once
+    let mut entries = BTreeMap::new();
+    for path in paths {
+        if shares.under(path).next().is_none() {
+            return Err(format!("{} does not exist", path));
+        }
+        for (name, file) in shares.under(path) {
+            entries.insert(name.clone(), file.size);
+        }
+        if entries.len() > MAX_ENTRIES {
+            return Err(format!("too many files, at most {} can be sent at once", MAX_ENTRIES));
+        }
+    }
+    Ok(entries.into_iter().map(|(path, size)| ManifestEntry { path, size }).collect())
+}
+
+// Turns a path from a manifest or a request into a relative path that stays inside the folder
+// it is joined onto, or None if it tries to leave it. Only plain names separated by / are allowed
+pub fn safe_path(path: &str) -> Option<PathBuf> {
+    let mut safe = PathBuf::new();
+    for part in path.split('/') {
+        // a backslash or drive letter would let a name like ..\x or C:x escape on Windows
+        if part.contains(['\\', ':']) {
+            return None;
+        }
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => safe.push(name),
+            _ => return None,
+        }
+    }
+    Some(safe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_path_keeps_plain_relative_paths() {
+        assert_eq!(safe_path("notes.txt"), Some(PathBuf::from("notes.txt")));
+        assert_eq!(safe_path("photos/2024/beach.jpg"), Some(PathBuf::from("photos/2024/beach.jpg")));
+    }
+
+    #[test]
+    fn safe_path_rejects_parent_dirs() {
+        assert_eq!(safe_path(".."), None);
+        assert_eq!(safe_path("../secret"), None);
+        assert_eq!(safe_path("photos/../../secret"), None);
+        assert_eq!(safe_path("."), None);
+        assert_eq!(safe_path("./notes.txt"), None);
+    }
+
+    #[test]
+    fn safe_path_rejects_absolute_and_empty_paths() {
+        assert_eq!(safe_path("/etc/passwd"), None);
+        assert_eq!(safe_path(""), None);
+        assert_eq!(safe_path("photos//beach.jpg"), None);
+        assert_eq!(safe_path("photos/"), None);
+    }
+
+    #[test]
+    fn safe_path_rejects_windows_paths() {
+        assert_eq!(safe_path("..\\secret"), None);
+        assert_eq!(safe_path("C:secret"), None);
+        assert_eq!(safe_path("C:\\Windows"), None);
+    }
+}
@@ -0,0 +1,447 @@
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::kad::{self, QueryId};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::persistent_store::PersistentStore;
+use super::state::ChatState;
+
+// How long a cached nickname is trusted before it is fetched from the DHT again
+pub const NICKNAME_TTL: Duration = Duration::from_secs(5 * 60);
+// How often cached nicknames of connected peers are checked for staleness
+pub const NICKNAME_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// How long other peers keep our nickname record (and the provider record for the name) before dropping it
+pub const NICKNAME_RECORD_TTL: Duration = Duration::from_secs(60 * 60);
+// We republish well within the TTL so the record never lapses while we are online
+pub const NICKNAME_REPUBLISH_INTERVAL: Duration = Duration::from_secs(20 * 60);
+// A peer joining triggers a republish, but not more often than this
+pub const NICKNAME_REPUBLISH_COOLDOWN: Duration = Duration::from_secs(60);
+
+// Nicknames are stored under the peer id (peer id -> nickname), and each peer also registers
+// itself as a provider of its nickname key so others can go the other way (nickname -> peer ids)
+pub fn nickname_record_key(peer_id: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&peer_id.to_string())
+}
+
+pub fn nickname_provider_key(nickname: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("nickname:{}", normalize(nickname)))
+}
+
+// Nicknames are matched ignoring case. The provider key and the comparisons have to agree on
+// what that means, or a lookup finds providers it then throws away
+fn normalize(nickname: &str) -> String {
+    nickname.to_lowercase()
+}
+
+fn same_nickname(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+// The value of a nickname record, signed by the key of the peer it belongs to so nobody else can overwrite it.
+// `published` is signed too, so an old record (say from before a rename) can't be passed off as the latest,
+// and so is `expires`, so a withdrawn or lapsed record can't be stored again with a later expiry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedNickname {
+    pub nickname: String,
+    // milliseconds since the unix epoch
+    pub published: u64,
+    pub expires: u64,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+fn signing_payload(peer_id: &PeerId, nickname: &str, published: u64, expires: u64) -> Vec<u8> {
+    let mut payload = peer_id.to_bytes();
+    payload.extend_from_slice(&published.to_be_bytes());
+    payload.extend_from_slice(&expires.to_be_bytes());
+    payload.extend_from_slice(nickname.as_bytes());
+    payload
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub fn signed_nickname_record(keypair: &Keypair, nickname: &str, ttl: Duration) -> Result<kad::Record, SigningError> {
+    let peer_id = keypair.public().to_peer_id();
+    let published = unix_millis();
+    let expires = published + ttl.as_millis() as u64;
+    let signed = SignedNickname {
+        nickname: nickname.to_string(),
+        published,
+        expires,
+        public_key: keypair.public().encode_protobuf(),
+        signature: keypair.sign(&signing_payload(&peer_id, nickname, published, expires))?,
+    };
+    Ok(kad::Record {
+        key: nickname_record_key(&peer_id),
+        value: serde_json::to_vec(&signed).expect("nickname record serializes"),
+        publisher: Some(peer_id),
+        expires: Some(Instant::now() + ttl),
+    })
+}
+
+// A nickname record whose signature checked out
+pub struct VerifiedNickname {
+    pub peer_id: PeerId,
+    pub nickname: String,
+    pub published: u64,
+    pub expires: u64,
+}
+
+impl VerifiedNickname {
+    // An expired record means the peer has no nickname, it either withdrew it or stopped republishing
+    pub fn is_expired(&self) -> bool {
+        self.expires <= unix_millis()
+    }
+}
+
+// Checks a nickname record was signed by the peer whose key it is stored under,
+// returning that peer and its nickname. Forged or malformed records give None.
+pub fn verify_nickname_record(record: &kad::Record) -> Option<VerifiedNickname> {
+    let peer_id = PeerId::from_str(std::str::from_utf8(record.key.as_ref()).ok()?).ok()?;
+    if record.publisher.is_some_and(|publisher| publisher != peer_id) {
+        return None;
+    }
+    let signed: SignedNickname = serde_json::from_slice(&record.value).ok()?;
+    let public_key = PublicKey::try_decode_protobuf(&signed.public_key).ok()?;
+    if public_key.to_peer_id() != peer_id {
+        return None;
+    }
+    if !public_key.verify(&signing_payload(&peer_id, &signed.nickname, signed.published, signed.expires), &signed.signature) {
+        return None;
+    }
+    Some(VerifiedNickname { peer_id, nickname: signed.nickname, published: signed.published, expires: signed.expires })
+}
+
+// True if `record` is older than the copy of the same peer's record we already have, i.e. a replay
+pub fn is_outdated(record: &VerifiedNickname, stored: Option<&kad::Record>) -> bool {
+    stored
+        .and_then(verify_nickname_record)
+        .is_some_and(|stored| stored.published > record.published)
+}
+
+// When a nickname record someone asks us to store should be dropped, going by its signed expiry rather than
+// the one it was sent with. None if it has already expired. A withdrawal is expired from the start, we keep
+// it until any record it replaced would have expired too, so that one can't be put back in the meantime
+pub fn keep_until(record: &VerifiedNickname) -> Option<Instant> {
+    let now = unix_millis();
+    let until = if record.is_expired() {
+        record.published + NICKNAME_RECORD_TTL.as_millis() as u64
+    } else {
+        record.expires
+    };
+    (until > now).then(|| Instant::now() + Duration::from_millis(until - now))
+}
+
+pub fn publish_nickname(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    keypair: &Keypair,
+    old_nickname: Option<&str>,
+    nickname: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(old) = old_nickname {
+        kademlia.stop_providing(&nickname_provider_key(old));
+    }
+    let record = signed_nickname_record(keypair, nickname, NICKNAME_RECORD_TTL)?;
+    kademlia.put_record(record, kad::Quorum::One)?;
+    kademlia.start_providing(nickname_provider_key(nickname))?;
+    Ok(())
+}
+
+// Publishes our nickname again with a fresh expiry, unless we already did within `min_interval`
+pub fn republish_nickname(kademlia: &mut kad::Behaviour<PersistentStore>, state: &mut ChatState, min_interval: Duration) {
+    if state.nickname_published.is_some_and(|at| at.elapsed() < min_interval) {
+        return;
+    }
+    match publish_nickname(kademlia, &state.keypair, None, &state.nickname) {
+        Ok(()) => state.nickname_published = Some(Instant::now()),
+        Err(e) => eprintln!("Failed to store record: {:?}", e),
+    }
+}
+
+// Withdraws our nickname when shutting down. Kademlia has no delete, so the record is published
+// again already expired, which the peers holding it take to mean we have no nickname.
+pub fn unpublish_nickname(
+    kademlia: &mut kad::Behaviour<PersistentStore>,
+    keypair: &Keypair,
+    nickname: &str,
+) -> Result<QueryId, Box<dyn std::error::Error>> {
+    kademlia.stop_providing(&nickname_provider_key(nickname));
+    let record = signed_nickname_record(keypair, nickname, Duration::ZERO)?;
+    let key = record.key.clone();
+    let query_id = kademlia.put_record(record, kad::Quorum::One)?;
+    kademlia.remove_record(&key);
+    Ok(query_id)
+}
+
+pub enum Resolved {
+    Peer(PeerId),
+    Ambiguous(Vec<PeerId>),
+    Unknown,
+}
+
+// A command waiting on a DHT lookup of a nickname, re-run once the providers are known
+pub struct PendingLookup {
+    pub nickname: String,
+    pub line: String,
+    pub found: HashSet<PeerId>,
+    // set once the providers are known and their nickname records are being fetched
+    checking: bool,
+    // how many of those records haven't come back yet
+    unchecked: usize,
+}
+
+pub struct CachedNickname {
+    pub nickname: String,
+    pub fetched: Instant,
+    // when the record it came from was published, an older record never replaces a newer one
+    pub published: u64,
+}
+
+// Local cache of nicknames we have seen, so messages can be shown without a DHT lookup each time
+// and commands can refer to peers as @nickname
+#[derive(Default)]
+pub struct Contacts {
+    nicknames: HashMap<PeerId, CachedNickname>,
+    // peers whose last lookup found no nickname record, so busy rooms don't keep asking the DHT for them
+    not_found: HashMap<PeerId, Instant>,
+    pending: HashMap<QueryId, PendingLookup>,
+    // record lookups checking the providers found by a pending lookup, and which lookup they are for
+    checking: HashMap<QueryId, QueryId>,
+    // lookups of a peer's nickname record, and when they started
+    refreshing: HashMap<QueryId, (PeerId, Instant)>,
+}
+
+impl Contacts {
+    pub fn insert(&mut self, record: VerifiedNickname) {
+        self.not_found.remove(&record.peer_id);
+        if let Some(cached) = self.nicknames.get_mut(&record.peer_id) {
+            if cached.published > record.published {
+                // still counts as fetched, we just already knew better
+                cached.fetched = Instant::now();
+                return;
+            }
+        }
+        if record.is_expired() {
+            self.nicknames.remove(&record.peer_id);
+            self.not_found.insert(record.peer_id, Instant::now());
+            return;
+        }
+        let cached = CachedNickname { nickname: record.nickname, fetched: Instant::now(), published: record.published };
+        self.nicknames.insert(record.peer_id, cached);
+    }
+
+    pub fn nickname(&self, peer_id: &PeerId) -> Option<&str> {
+        self.nicknames.get(peer_id).map(|cached| cached.nickname.as_str())
+    }
+
+    // The nickname of a peer if we know it, otherwise its peer id
+    pub fn display_name(&self, peer_id: &PeerId) -> String {
+        self.nickname(peer_id)
+            .map(str::to_string)
+            .unwrap_or_else(|| peer_id.to_string())
+    }
+
+    // True if the peer's nickname is missing or older than the TTL, nobody is already fetching it
+    // and we haven't just looked and found nothing
+    pub fn needs_refresh(&self, peer_id: &PeerId) -> bool {
+        if self.refreshing.values().any(|(p, _)| p == peer_id) {
+            return false;
+        }
+        if self.not_found.get(peer_id).is_some_and(|at| at.elapsed() < NICKNAME_TTL) {
+            return false;
+        }
+        match self.nicknames.get(peer_id) {
+            Some(cached) => cached.fetched.elapsed() > NICKNAME_TTL,
+            None => true,
+        }
+    }
+
+    pub fn start_refresh(&mut self, query_id: QueryId, peer_id: PeerId) {
+        self.refreshing.insert(query_id, (peer_id, Instant::now()));
+    }
+
+    // Called when a lookup finishes, remembering the peer had no record if none came back while it ran
+    pub fn finish_refresh(&mut self, query_id: &QueryId) -> Option<PeerId> {
+        let (peer_id, started) = self.refreshing.remove(query_id)?;
+        if self.nicknames.get(&peer_id).is_none_or(|cached| cached.fetched < started) {
+            // forget old misses as we go, the map would otherwise hold every peer ever heard from
+            self.not_found.retain(|_, at| at.elapsed() < NICKNAME_TTL);
+            self.not_found.insert(peer_id, Instant::now());
+        }
+        Some(peer_id)
+    }
+
+    pub fn resolve(&self, nickname: &str) -> Resolved {
+        let mut matches: Vec<PeerId> = self.nicknames
+            .iter()
+            .filter(|(_, cached)| same_nickname(&cached.nickname, nickname))
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        match matches.len() {
+            0 => Resolved::Unknown,
+            1 => Resolved::Peer(matches.remove(0)),
+            _ => {
+                matches.sort();
+                Resolved::Ambiguous(matches)
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &String)> {
+        self.nicknames.iter().map(|(peer_id, cached)| (peer_id, &cached.nickname))
+    }
+
+    pub fn start_lookup(&mut self, query_id: QueryId, nickname: String, line: String) {
+        self.pending.insert(query_id, PendingLookup { nickname, line, found: HashSet::new(), checking: false, unchecked: 0 });
+    }
+
+    pub fn lookup_progress(&mut self, query_id: &QueryId, providers: HashSet<PeerId>) {
+        if let Some(lookup) = self.pending.get_mut(query_id) {
+            lookup.found.extend(providers);
+        }
+    }
+
+    // Finishes a lookup, keeping only the providers whose signed record says they have the nickname
+    fn finish_lookup(&mut self, query_id: &QueryId) -> Option<PendingLookup> {
+        let mut lookup = self.pending.remove(query_id)?;
+        lookup.found.retain(|peer_id| self.nickname(peer_id).is_some_and(|name| same_nickname(name, &lookup.nickname)));
+        Some(lookup)
+    }
+}
+
+// Called when a Kademlia query finishes. Anyone can say they provide a nickname, so once a nickname lookup
+// has its providers we fetch each one's signed record, and the lookup is only done when those are in.
+// Returns the finished lookup with the providers whose records checked out
+pub fn query_finished(kademlia: &mut kad::Behaviour<PersistentStore>, contacts: &mut Contacts, query_id: &QueryId) -> Option<PendingLookup> {
+    contacts.finish_refresh(query_id);
+    let lookup_id = match contacts.checking.remove(query_id) {
+        Some(lookup_id) => {
+            let lookup = contacts.pending.get_mut(&lookup_id)?;
+            lookup.unchecked -= 1;
+            lookup_id
+        }
+        None => {
+            let lookup = contacts.pending.get_mut(query_id)?;
+            lookup.checking = true;
+            let providers: Vec<PeerId> = lookup.found.iter().cloned().collect();
+            for peer_id in providers {
+                // a nickname fetched recently has already been checked
+                let known = contacts.nicknames.get(&peer_id).is_some_and(|cached| cached.fetched.elapsed() < NICKNAME_TTL);
+                if known {
+                    continue;
+                }
+                let record_query = kademlia.get_record(nickname_record_key(&peer_id));
+                contacts.start_refresh(record_query, peer_id);
+                contacts.checking.insert(record_query, *query_id);
+                contacts.pending.get_mut(query_id)?.unchecked += 1;
+            }
+            *query_id
+        }
+    };
+    match contacts.pending.get(&lookup_id) {
+        Some(lookup) if lookup.checking && lookup.unchecked == 0 => contacts.finish_lookup(&lookup_id),
+        _ => None,
+    }
+}
+
+// Fetches a peer's nickname record from the DHT if the cached copy is missing or stale
+pub fn refresh_nickname(kademlia: &mut kad::Behaviour<PersistentStore>, contacts: &mut Contacts, peer_id: PeerId) {
+    if contacts.needs_refresh(&peer_id) {
+        let query_id = kademlia.get_record(nickname_record_key(&peer_id));
+        contacts.start_refresh(query_id, peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a record with a chosen publish time, signed_nickname_record always uses the current time
+    fn record_at(keypair: &Keypair, nickname: &str, published: u64, expires: u64) -> kad::Record {
+        let peer_id = keypair.public().to_peer_id();
+        let signed = SignedNickname {
+            nickname: nickname.to_string(),
+            published,
+            expires,
+            public_key: keypair.public().encode_protobuf(),
+            signature: keypair.sign(&signing_payload(&peer_id, nickname, published, expires)).unwrap(),
+        };
+        kad::Record {
+            key: nickname_record_key(&peer_id),
+            value: serde_json::to_vec(&signed).unwrap(),
+            publisher: Some(peer_id),
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn signed_record_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let record = signed_nickname_record(&keypair, "alice", NICKNAME_RECORD_TTL).unwrap();
+        let verified = verify_nickname_record(&record).unwrap();
+        assert_eq!(verified.peer_id, keypair.public().to_peer_id());
+        assert_eq!(verified.nickname, "alice");
+        assert!(!verified.is_expired());
+    }
+
+    #[test]
+    fn tampered_record_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let record = signed_nickname_record(&keypair, "alice", NICKNAME_RECORD_TTL).unwrap();
+        let mut signed: SignedNickname = serde_json::from_slice(&record.value).unwrap();
+
+        let mut renamed = signed.clone();
+        renamed.nickname = "mallory".to_string();
+        let tampered = kad::Record { value: serde_json::to_vec(&renamed).unwrap(), ..record.clone() };
+        assert!(verify_nickname_record(&tampered).is_none());
+
+        // pushing the expiry back, e.g. to undo a withdrawal, breaks the signature too
+        signed.expires += 60 * 60 * 1000;
+        let extended = kad::Record { value: serde_json::to_vec(&signed).unwrap(), ..record };
+        assert!(verify_nickname_record(&extended).is_none());
+    }
+
+    #[test]
+    fn record_under_another_peers_key_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let record = signed_nickname_record(&keypair, "alice", NICKNAME_RECORD_TTL).unwrap();
+        let other = PeerId::random();
+        let moved = kad::Record { key: nickname_record_key(&other), publisher: None, ..record.clone() };
+        assert!(verify_nickname_record(&moved).is_none());
+        let republished = kad::Record { publisher: Some(other), ..record };
+        assert!(verify_nickname_record(&republished).is_none());
+    }
+
+    #[test]
+    fn older_record_is_outdated() {
+        let keypair = Keypair::generate_ed25519();
+        let now = unix_millis();
+        let ttl = NICKNAME_RECORD_TTL.as_millis() as u64;
+        let old = record_at(&keypair, "alice", now - 1000, now - 1000 + ttl);
+        let new = record_at(&keypair, "alice2", now, now + ttl);
+        let old_verified = verify_nickname_record(&old).unwrap();
+        let new_verified = verify_nickname_record(&new).unwrap();
+        assert!(is_outdated(&old_verified, Some(&new)));
+        assert!(!is_outdated(&new_verified, Some(&old)));
+        assert!(!is_outdated(&old_verified, None));
+    }
+
+    #[test]
+    fn expired_records_are_only_kept_as_withdrawals() {
+        let keypair = Keypair::generate_ed25519();
+        let now = unix_millis();
+        let ttl = NICKNAME_RECORD_TTL.as_millis() as u64;
+        // withdrawn just now, kept so the record it replaced can't come back
+        let withdrawn = verify_nickname_record(&record_at(&keypair, "alice", now, now)).unwrap();
+        assert!(withdrawn.is_expired());
+        assert!(keep_until(&withdrawn).is_some());
+        // lapsed long ago, nothing to keep
+        let lapsed = verify_nickname_record(&record_at(&keypair, "alice", now - 3 * ttl, now - 2 * ttl)).unwrap();
+        assert!(keep_until(&lapsed).is_none());
+    }
+}
@@ -0,0 +1,38 @@
+use libp2p::{identify, Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// What we have learned about a connected peer from identify and ping
+#[derive(Default)]
+pub struct PeerInfo {
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub rtt: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct PeerBook {
+    peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerBook {
+    pub fn identified(&mut self, peer_id: PeerId, info: &identify::Info) {
+        let entry = self.peers.entry(peer_id).or_default();
+        entry.agent_version = Some(info.agent_version.clone());
+        entry.protocol_version = Some(info.protocol_version.clone());
+        entry.listen_addrs = info.listen_addrs.clone();
+    }
+
+    pub fn pinged(&mut self, peer_id: PeerId, rtt: Duration) {
+        self.peers.entry(peer_id).or_default().rtt = Some(rtt);
+    }
+
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer_id)
+    }
+}
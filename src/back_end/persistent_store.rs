@@ -0,0 +1,280 @@
+use libp2p::kad::store::{MemoryStore, RecordStore, Result};
+use libp2p::identity::Keypair;
+use libp2p::kad::{self, ProviderRecord, Record, RecordKey};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often the record store and routing table are written to the data directory
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+const RECORDS_FILE: &str = "records.json";
+const ROUTING_TABLE_FILE: &str = "routing_table.json";
+const IDENTITY_FILE: &str = "identity.key";
+
+// Records are kept in memory like MemoryStore, and written to the data directory so they
+// survive a restart. Only the save has to touch the disk, lookups stay in memory.
+pub struct PersistentStore {
+    inner: MemoryStore,
+    dir: PathBuf,
+    // MemoryStore can't list every provider record, so remember which keys have them
+    provider_keys: HashSet<RecordKey>,
+    dirty: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<String>,
+    // seconds since the unix epoch, Instant can't be saved
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+    key: Vec<u8>,
+    provider: String,
+    expires: Option<u64>,
+    addresses: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoredRecords {
+    records: Vec<StoredRecord>,
+    providers: Vec<StoredProvider>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    peer_id: String,
+    addresses: Vec<String>,
+}
+
+fn instant_to_unix(instant: Instant) -> u64 {
+    let remaining = instant.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Returns None if the time has already passed, the record has expired
+fn unix_to_instant(secs: u64) -> Option<Instant> {
+    let at = UNIX_EPOCH + Duration::from_secs(secs);
+    let remaining = at.duration_since(SystemTime::now()).ok()?;
+    Some(Instant::now() + remaining)
+}
+
+impl PersistentStore {
+    pub fn open(local_id: PeerId, dir: &Path) -> Self {
+        let mut store = PersistentStore {
+            inner: MemoryStore::new(local_id),
+            dir: dir.to_path_buf(),
+            provider_keys: HashSet::new(),
+            dirty: false,
+        };
+        let Ok(bytes) = std::fs::read(dir.join(RECORDS_FILE)) else {
+            return store;
+        };
+        let stored: StoredRecords = match serde_json::from_slice(&bytes) {
+            Ok(stored) => stored,
+            Err(e) => {
+                eprintln!("Warning: could not read saved records, starting empty - {}", e);
+                return store;
+            }
+        };
+        for record in stored.records {
+            let expires = match record.expires {
+                Some(secs) => match unix_to_instant(secs) {
+                    Some(instant) => Some(instant),
+                    None => continue,
+                },
+                None => None,
+            };
+            let publisher = record.publisher.and_then(|p| PeerId::from_str(&p).ok());
+            let _ = store.inner.put(Record {
+                key: RecordKey::from(record.key),
+                value: record.value,
+                publisher,
+                expires,
+            });
+        }
+        for provider in stored.providers {
+            let Ok(provider_id) = PeerId::from_str(&provider.provider) else {
+                continue;
+            };
+            let expires = match provider.expires {
+                Some(secs) => match unix_to_instant(secs) {
+                    Some(instant) => Some(instant),
+                    None => continue,
+                },
+                None => None,
+            };
+            let key = RecordKey::from(provider.key);
+            store.provider_keys.insert(key.clone());
+            let _ = store.inner.add_provider(ProviderRecord {
+                key,
+                provider: provider_id,
+                expires,
+                addresses: provider.addresses.iter().filter_map(|a| a.parse().ok()).collect(),
+            });
+        }
+        store
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Writes the records to disk if anything changed since the last save
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let records = self.inner.records()
+            .map(|record| StoredRecord {
+                key: record.key.to_vec(),
+                value: record.value.clone(),
+                publisher: record.publisher.map(|p| p.to_string()),
+                expires: record.expires.map(instant_to_unix),
+            })
+            .collect();
+        self.provider_keys.retain(|key| !self.inner.providers(key).is_empty());
+        let providers = self.provider_keys
+            .iter()
+            .flat_map(|key| self.inner.providers(key))
+            .map(|provider| StoredProvider {
+                key: provider.key.to_vec(),
+                provider: provider.provider.to_string(),
+                expires: provider.expires.map(instant_to_unix),
+                addresses: provider.addresses.iter().map(|a| a.to_string()).collect(),
+            })
+            .collect();
+        let stored = StoredRecords { records, providers };
+        write_json(&self.dir.join(RECORDS_FILE), &stored)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Writes a file only we can read, and never replaces one that is already there
+pub fn write_secret(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+// Our keypair, and so our peer ID, is kept in the data directory. Without it the saved records, routing
+// table and blocklist would all be about a node that no longer exists after a restart
+pub fn load_keypair(dir: &Path) -> std::result::Result<Keypair, Box<dyn Error>> {
+    let path = dir.join(IDENTITY_FILE);
+    match std::fs::read(&path) {
+        Ok(bytes) => Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|e| format!("{:?} is not a valid identity key, move it away to start with a new identity: {}", path, e).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            write_secret(&path, &keypair.to_protobuf_encoding()?)?;
+            println!("Created a new identity in {:?}", path);
+            Ok(keypair)
+        }
+        Err(e) => Err(format!("Failed to read the identity key {:?}: {}", path, e).into()),
+    }
+}
+
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // write to a temp file first so a crash mid-write doesn't lose the old copy
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(value)?)?;
+    std::fs::rename(tmp, path)
+}
+
+impl RecordStore for PersistentStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> Result<()> {
+        self.dirty = true;
+        self.inner.put(r)
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.dirty = true;
+        self.inner.remove(k)
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        self.dirty = true;
+        self.provider_keys.insert(record.key.clone());
+        self.inner.add_provider(record)
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.dirty = true;
+        self.inner.remove_provider(k, p)
+    }
+}
+
+// Saves the records and a snapshot of the routing table, so a restarted node knows who to reconnect to
+pub fn save(kademlia: &mut kad::Behaviour<PersistentStore>) -> std::io::Result<()> {
+    let peers: Vec<StoredPeer> = kademlia.kbuckets()
+        .flat_map(|bucket| {
+            bucket.iter()
+                .map(|entry| StoredPeer {
+                    peer_id: entry.node.key.preimage().to_string(),
+                    addresses: entry.node.value.iter().map(|a| a.to_string()).collect(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let store = kademlia.store_mut();
+    write_json(&store.dir().join(ROUTING_TABLE_FILE), &peers)?;
+    store.save()
+}
+
+// Reads back the routing table saved by a previous run
+pub fn load_routing_table(dir: &Path) -> Vec<(PeerId, Vec<Multiaddr>)> {
+    let Ok(bytes) = std::fs::read(dir.join(ROUTING_TABLE_FILE)) else {
+        return Vec::new();
+    };
+    let peers: Vec<StoredPeer> = serde_json::from_slice(&bytes).unwrap_or_default();
+    peers.into_iter()
+        .filter_map(|peer| {
+            let peer_id = PeerId::from_str(&peer.peer_id).ok()?;
+            let addresses = peer.addresses.iter().filter_map(|a| a.parse().ok()).collect();
+            Some((peer_id, addresses))
+        })
+        .collect()
+}
@@ -0,0 +1,338 @@
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+
+use super::shares::ShareIndex;
+use super::utils::format_size;
+
+// Only this much of a file is read to make a preview, whatever is in it
+const SAMPLE_SIZE: u64 = 64 * 1024;
+// and at most this much of a text file is sent
+const TEXT_PREVIEW: usize = 1024;
+const TEXT_LINES: usize = 20;
+
+// Asks for a preview of a shared file, by name or by hash like a file request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviewRequest {
+    pub name: String,
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PreviewContent {
+    // the start of a text file
+    Text(String),
+    Image { format: String, width: u32, height: u32 },
+    // audio and video, with whatever the file's headers say about it, e.g. the duration or the artist
+    Media { format: String, details: Vec<String> },
+    // nothing we know how to preview, or the owner has turned previews off for it
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviewResponse {
+    pub name: String,
+    pub size: u64,
+    pub hash: Option<String>,
+    pub content: PreviewContent,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl PreviewResponse {
+    fn error(request: &PreviewRequest, error: &str) -> Self {
+        PreviewResponse {
+            name: request.name.clone(),
+            size: 0,
+            hash: None,
+            content: PreviewContent::None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    pub fn build(request: &PreviewRequest, shares: &ShareIndex) -> Self {
+        let found = match &request.hash {
+            Some(hash) => shares.find_hash(hash),
+            None => shares.get(&request.name).map(|file| (&request.name, file)),
+        };
+        let Some((name, file)) = found else {
+            return PreviewResponse::error(request, "file does not exist");
+        };
+        let content = match file.preview {
+            true => read_sample(&file.path(name))
+                .map(|sample| sniff(name, &sample))
+                .unwrap_or(PreviewContent::None),
+            false => PreviewContent::None,
+        };
+        PreviewResponse { name: name.clone(), size: file.size, hash: file.hash.clone(), content, error: None }
+    }
+
+    // Everything in a preview is printed straight to the terminal, so like a chat message it can't have
+    // control characters that could mess with it. Line breaks in the start of a text file are fine
+    pub fn check(&self) -> Result<(), &'static str> {
+        let mut strings = vec![self.name.as_str()];
+        strings.extend(self.hash.as_deref());
+        strings.extend(self.error.as_deref());
+        match &self.content {
+            PreviewContent::Text(text) => strings.extend(text.split('\n')),
+            PreviewContent::Image { format, .. } => strings.push(format),
+            PreviewContent::Media { format, details } => {
+                strings.push(format);
+                strings.extend(details.iter().map(String::as_str));
+            }
+            PreviewContent::None => {}
+        }
+        match strings.iter().any(|s| s.chars().any(char::is_control)) {
+            true => Err("the preview contains control characters"),
+            false => Ok(()),
+        }
+    }
+
+    pub fn print(&self, peer: &str) {
+        if let Some(error) = &self.error {
+            println!("No preview of {} from {}: {}", self.name, peer, error);
+            return;
+        }
+        println!("Preview of {} from {} ({})", self.name, peer, format_size(self.size));
+        if let Some(hash) = &self.hash {
+            println!("sha256 {}", hash);
+        }
+        match &self.content {
+            PreviewContent::Text(text) => {
+                println!("----");
+                println!("{}", text);
+                println!("----");
+            }
+            PreviewContent::Image { format, width, height } => println!("{} image, {}x{}", format, width, height),
+            PreviewContent::Media { format, details } => {
+                println!("{}", format);
+                for detail in details {
+                    println!("    {}", detail);
+                }
+            }
+            PreviewContent::None => println!("No preview available"),
+        }
+    }
+}
+
+fn read_sample(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut sample = Vec::new();
+    std::fs::File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    Ok(sample)
+}
+
+// Works out what a file is from its first bytes, falling back on its extension
+fn sniff(name: &str, sample: &[u8]) -> PreviewContent {
+    if let Some(image) = image_size(sample) {
+        return image;
+    }
+    if let Some(media) = media_info(sample) {
+        return media;
+    }
+    if let Some(text) = text_start(sample) {
+        return text;
+    }
+    let extension = Path::new(name).extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some(ext @ ("mp3" | "ogg" | "opus" | "m4a" | "aac" | "mkv" | "webm" | "avi" | "mov")) => {
+            PreviewContent::Media { format: ext.to_uppercase(), details: Vec::new() }
+        }
+        _ => PreviewContent::None,
+    }
+}
+
+fn text_start(sample: &[u8]) -> Option<PreviewContent> {
+    if sample.is_empty() || sample.contains(&0) {
+        return None;
+    }
+    let head = &sample[..sample.len().min(TEXT_PREVIEW)];
+    // the cut may land in the middle of a character, which is fine as long as the rest is valid
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let text: Vec<String> = text.lines().take(TEXT_LINES).map(printable).collect();
+    Some(PreviewContent::Text(text.join("\n")))
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn le16(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn be32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn image(format: &str, width: u32, height: u32) -> Option<PreviewContent> {
+    Some(PreviewContent::Image { format: format.to_string(), width, height })
+}
+
+// Image dimensions from the header, for the common formats
+fn image_size(data: &[u8]) -> Option<PreviewContent> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return image("PNG", be32(data, 16)?, be32(data, 20)?);
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return image("GIF", le16(data, 6)?, le16(data, 8)?);
+    }
+    if data.starts_with(b"BM") {
+        // the height is negative for images stored top down
+        return image("BMP", le32(data, 18)?, (le32(data, 22)? as i32).unsigned_abs());
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return match data.get(12..16)? {
+            b"VP8 " => image("WebP", le16(data, 26)? & 0x3fff, le16(data, 28)? & 0x3fff),
+            b"VP8L" => {
+                let bits = le32(data, 21)?;
+                image("WebP", (bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1)
+            }
+            b"VP8X" => {
+                let width = le32(data, 24)? & 0xff_ffff;
+                let height = le32(data, 27)? & 0xff_ffff;
+                image("WebP", width + 1, height + 1)
+            }
+            _ => None,
+        };
+    }
+    if data.starts_with(&[0xff, 0xd8]) {
+        // walk the JPEG segments until the frame header, which has the size in it
+        let mut at = 2;
+        while at + 9 < data.len() {
+            if data[at] != 0xff {
+                return None;
+            }
+            let marker = data[at + 1];
+            let len = be16(data, at + 2)? as usize;
+            if matches!(marker, 0xc0..=0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf) {
+                return image("JPEG", be16(data, at + 7)?, be16(data, at + 5)?);
+            }
+            at += 2 + len;
+        }
+    }
+    None
+}
+
+fn duration(secs: u64) -> String {
+    format!("duration {}:{:02}", secs / 60, secs % 60)
+}
+
+// Audio and video details from the container headers
+fn media_info(data: &[u8]) -> Option<PreviewContent> {
+    let media = |format: &str, details: Vec<String>| Some(PreviewContent::Media { format: format.to_string(), details });
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+        // the fmt chunk is nearly always first
+        let channels = le16(data, 22)?;
+        let rate = le32(data, 24)?;
+        let bits = le16(data, 34)?;
+        let mut details = vec![format!("{} channels, {} Hz, {} bit", channels, rate, bits)];
+        if let (Some(bytes_per_sec), Some(data_len)) = (le32(data, 28), le32(data, 40)) {
+            details.extend(data_len.checked_div(bytes_per_sec).map(|secs| duration(secs as u64)));
+        }
+        return media("WAV audio", details);
+    }
+    if data.starts_with(b"fLaC") {
+        // STREAMINFO: 20 bits of sample rate, 3 of channels, 5 of bits per sample, 36 of samples
+        let info = data.get(18..26)?;
+        let rate = (info[0] as u32) << 12 | (info[1] as u32) << 4 | (info[2] as u32) >> 4;
+        let channels = ((info[2] >> 1) & 0x7) + 1;
+        let samples = ((info[3] & 0x0f) as u64) << 32 | be32(info, 4)? as u64;
+        let mut details = vec![format!("{} channels, {} Hz", channels, rate)];
+        details.extend(samples.checked_div(rate as u64).map(duration));
+        return media("FLAC audio", details);
+    }
+    if data.starts_with(b"ID3") {
+        return media("MP3 audio", id3_tags(data));
+    }
+    if data.starts_with(b"OggS") {
+        return media("Ogg audio", Vec::new());
+    }
+    if data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        return media("Matroska/WebM video", Vec::new());
+    }
+    if data.get(4..8) == Some(b"ftyp") {
+        let brand = printable(String::from_utf8_lossy(data.get(8..12)?).trim());
+        let mut details = vec![format!("brand {}", brand)];
+        // the movie header is only there if the index is at the start of the file
+        if let Some(at) = data.windows(4).position(|w| w == b"mvhd") {
+            let (timescale, length) = match data.get(at + 4)? {
+                0 => (be32(data, at + 16)?, be32(data, at + 20)? as u64),
+                _ => (be32(data, at + 24)?, (be32(data, at + 28)? as u64) << 32 | be32(data, at + 32)? as u64),
+            };
+            details.extend(length.checked_div(timescale as u64).map(duration));
+        }
+        return media("MP4 video", details);
+    }
+    None
+}
+
+// Title, artist and album from an ID3v2 tag
+fn id3_tags(data: &[u8]) -> Vec<String> {
+    let mut details = Vec::new();
+    let version = data.get(3).copied().unwrap_or(0);
+    let Some(size) = data.get(6..10).map(|b| b.iter().fold(0usize, |acc, b| acc << 7 | (*b & 0x7f) as usize)) else {
+        return details;
+    };
+    let end = (10 + size).min(data.len());
+    let mut at = 10;
+    // ID3v2.2 uses short frame headers that aren't worth the trouble
+    while version >= 3 && at + 10 <= end {
+        let id = &data[at..at + 4];
+        if id[0] == 0 {
+            break;
+        }
+        let Some(len) = (match version {
+            4 => data.get(at + 4..at + 8).map(|b| b.iter().fold(0usize, |acc, b| acc << 7 | (*b & 0x7f) as usize)),
+            _ => be32(data, at + 4).map(|l| l as usize),
+        }) else {
+            break;
+        };
+        let body = data.get(at + 10..(at + 10 + len).min(end)).unwrap_or_default();
+        let label = match id {
+            b"TIT2" => Some("title"),
+            b"TPE1" => Some("artist"),
+            b"TALB" => Some("album"),
+            _ => None,
+        };
+        if let (Some(label), Some(text)) = (label, id3_text(body)) {
+            details.push(format!("{} {}", label, text));
+        }
+        at += 10 + len;
+    }
+    details
+}
+
+fn id3_text(body: &[u8]) -> Option<String> {
+    let (encoding, text) = body.split_first()?;
+    let text = match encoding {
+        // UTF-16 with a byte order mark, or without one in big endian
+        1 | 2 => {
+            let units: Vec<u16> = text.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            let units = match units.first() {
+                Some(0xfeff) => units[1..].to_vec(),
+                Some(0xfffe) => units[1..].iter().map(|u| u.swap_bytes()).collect(),
+                _ => units.iter().map(|u| u.swap_bytes()).collect(),
+            };
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text).to_string(),
+        // latin-1, each byte is the character with that number
+        _ => text.iter().map(|b| *b as char).collect(),
+    };
+    let text = printable(text.trim_end_matches('\0').trim());
+    (!text.is_empty()).then_some(text)
+}
+
+// Drops control characters, the other side won't show a preview with any in it. Tabs become spaces
+fn printable(text: &str) -> String {
+    text.replace('\t', "    ").chars().filter(|c| !c.is_control()).collect()
+}
@@ -1,35 +1,163 @@
 use serde::{Deserialize, Serialize};
 use libp2p::{request_response, swarm::NetworkBehaviour, PeerId};
+use libp2p::request_response::OutboundRequestId;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PrivateMessage{
+    pub id: u64,
     pub message: String,
     pub sender: String,
 }
 
+// Everything sent over the private message protocol, either a message or a receipt for one we received
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrivateMessageRequest {
+    Message(PrivateMessage),
+    Receipt { id: u64, status: DeliveryStatus },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct PrivateMessageResponse(pub String);
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Read,
+    Rejected(String),
+    Failed(String),
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryStatus::Pending => write!(f, "pending"),
+            DeliveryStatus::Delivered => write!(f, "delivered"),
+            DeliveryStatus::Read => write!(f, "read"),
+            DeliveryStatus::Rejected(reason) => write!(f, "rejected ({reason})"),
+            DeliveryStatus::Failed(reason) => write!(f, "failed ({reason})"),
+        }
+    }
+}
+
+impl DeliveryStatus {
+    // How far along a message is, its status only ever moves forward. Receipts and acks can
+    // arrive in any order, e.g. a read receipt before the delivery ack or a timeout after both
+    fn rank(&self) -> u8 {
+        match self {
+            DeliveryStatus::Pending => 0,
+            DeliveryStatus::Rejected(_) | DeliveryStatus::Failed(_) => 1,
+            DeliveryStatus::Delivered => 2,
+            DeliveryStatus::Read => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivateMessageResponse {
+    pub id: u64,
+    pub status: DeliveryStatus,
+}
 
 #[derive(NetworkBehaviour)]
 pub struct PrivateMessageBehaviour {
-    pub request_response: libp2p::request_response::cbor::Behaviour<PrivateMessage, PrivateMessageResponse>
+    pub request_response: libp2p::request_response::cbor::Behaviour<PrivateMessageRequest, PrivateMessageResponse>
 }
 impl PrivateMessageBehaviour {
     pub fn send_request(
         &mut self,
         peer_id: PeerId,
-        request: PrivateMessage,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        request: PrivateMessageRequest,
+    ) -> Result<OutboundRequestId, Box<dyn std::error::Error>> {
         // Send a request to the peer using the `request_response` protocol
-        self.request_response.send_request(&peer_id, request);
-        Ok(())  
+        Ok(self.request_response.send_request(&peer_id, request))
     }
     pub async fn handle_request(
         &mut self,
         channel: request_response::ResponseChannel<PrivateMessageResponse>,
+        id: u64,
+        status: DeliveryStatus,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        
-        let private_message_response = PrivateMessageResponse("Recipient recieved message".to_string());
-        self.request_response.send_response(channel, private_message_response).unwrap();
+        let private_message_response = PrivateMessageResponse { id, status };
+        if self.request_response.send_response(channel, private_message_response).is_err() {
+            eprintln!("Failed to acknowledge private message {id}: connection closed");
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+pub struct SentMessage {
+    pub peer_id: PeerId,
+    pub message: String,
+    pub status: DeliveryStatus,
+}
+
+// Keeps track of the private messages we have sent so acknowledgements can be matched back to them
+#[derive(Default)]
+pub struct MessageTracker {
+    next_id: u64,
+    outbound: HashMap<OutboundRequestId, u64>,
+    sent: BTreeMap<u64, SentMessage>,
+    // messages we were sent and have printed, but the user hasn't typed anything since so may not have seen
+    unread: Vec<(PeerId, u64)>,
+}
+
+impl MessageTracker {
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub fn track(&mut self, request_id: OutboundRequestId, id: u64, peer_id: PeerId, message: String) {
+        self.outbound.insert(request_id, id);
+        self.sent.insert(id, SentMessage { peer_id, message, status: DeliveryStatus::Pending });
+    }
+
+    // Called with the response to one of our outbound requests, returns the message id if it was one of ours
+    pub fn acknowledge(&mut self, request_id: OutboundRequestId, response: PrivateMessageResponse) -> Option<u64> {
+        let id = self.outbound.remove(&request_id)?;
+        if id != response.id {
+            return None;
+        }
+        self.update(id, response.status)
+    }
+
+    // Called when the recipient sends us a receipt, only accepted if it comes from the peer we messaged
+    pub fn receipt(&mut self, peer_id: PeerId, id: u64, status: DeliveryStatus) -> Option<u64> {
+        match self.sent.get(&id) {
+            Some(sent) if sent.peer_id == peer_id => self.update(id, status),
+            _ => None,
+        }
+    }
+
+    pub fn fail(&mut self, request_id: OutboundRequestId, reason: String) -> Option<u64> {
+        let id = self.outbound.remove(&request_id)?;
+        self.update(id, DeliveryStatus::Failed(reason))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&SentMessage> {
+        self.sent.get(&id)
+    }
+
+    pub fn sent(&self) -> impl Iterator<Item = (&u64, &SentMessage)> {
+        self.sent.iter()
+    }
+
+    // A message we were sent has been printed, it is read once the user next types something
+    pub fn received(&mut self, peer_id: PeerId, id: u64) {
+        self.unread.push((peer_id, id));
+    }
+
+    pub fn take_unread(&mut self) -> Vec<(PeerId, u64)> {
+        std::mem::take(&mut self.unread)
+    }
+
+    // Returns the message id if the status changed, a status never goes backwards
+    fn update(&mut self, id: u64, status: DeliveryStatus) -> Option<u64> {
+        let sent = self.sent.get_mut(&id)?;
+        if status.rank() <= sent.status.rank() {
+            return None;
+        }
+        sent.status = status;
+        Some(id)
+    }
+}
@@ -0,0 +1,160 @@
+use libp2p::PeerId;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::nickname::Contacts;
+use super::throttle::{self, Direction};
+use super::utils::format_size;
+
+// Progress is reported at most this often for each transfer, so a fast download doesn't flood the chat
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+// The rate is worked out over this much recent history, so it follows changes in speed
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+// Keeps track of how far a transfer has got and how fast it is going
+pub struct ProgressMeter {
+    started: Instant,
+    samples: VecDeque<(Instant, u64)>,
+    last_report: Instant,
+}
+
+impl ProgressMeter {
+    // `done` is where the transfer starts from, more than 0 when it carries on from an earlier attempt
+    pub fn new(done: u64) -> Self {
+        let now = Instant::now();
+        ProgressMeter {
+            started: now,
+            samples: VecDeque::from([(now, done)]),
+            last_report: now,
+        }
+    }
+
+    // Records that `done` bytes have been transferred so far
+    pub fn update(&mut self, done: u64) {
+        let now = Instant::now();
+        self.samples.push_back((now, done));
+        // keep one sample older than the window so there is always something to measure from
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) > RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    // Bytes a second over the last few seconds
+    pub fn rate(&self) -> u64 {
+        let (Some((first_at, first)), Some((last_at, last))) = (self.samples.front(), self.samples.back()) else {
+            return 0;
+        };
+        let elapsed = last_at.duration_since(*first_at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0;
+        }
+        ((last - first) as f64 / elapsed) as u64
+    }
+
+    pub fn eta(&self, done: u64, total: u64) -> Option<Duration> {
+        let rate = self.rate();
+        if rate == 0 {
+            return None;
+        }
+        Some(Duration::from_secs(total.saturating_sub(done) / rate))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // True when it has been long enough since progress was last reported
+    pub fn due(&mut self) -> bool {
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return false;
+        }
+        self.last_report = Instant::now();
+        true
+    }
+
+    // Builds a progress event for the transfer, if one is due
+    pub fn progress(&mut self, done: u64, total: u64) -> Option<TransferEventKind> {
+        self.update(done);
+        if !self.due() {
+            return None;
+        }
+        Some(TransferEventKind::Progress { done, total, rate: self.rate(), eta: self.eta(done, total) })
+    }
+}
+
+pub enum TransferEventKind {
+    Started,
+    Progress {
+        done: u64,
+        total: u64,
+        rate: u64,
+        eta: Option<Duration>,
+    },
+    Completed {
+        total: u64,
+        elapsed: Duration,
+        // where a download was saved
+        path: Option<PathBuf>,
+    },
+    Failed(String),
+}
+
+// Something that happened to a download or upload, reported by the file transfer module
+// and shown to the user by the event loop
+pub struct TransferEvent {
+    // the number in /transfers, uploads don't have one
+    pub id: Option<u64>,
+    pub direction: Direction,
+    pub peer: PeerId,
+    pub filename: String,
+    pub kind: TransferEventKind,
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, (s % 3600) / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+pub fn print_event(event: &TransferEvent, contacts: &Contacts) {
+    let name = match event.id {
+        Some(id) => format!("#{} {}", id, event.filename),
+        None => event.filename.clone(),
+    };
+    let (verb, preposition) = match event.direction {
+        Direction::Down => ("Downloading", "from"),
+        Direction::Up => ("Sending", "to"),
+    };
+    let peer = contacts.display_name(&event.peer);
+    match &event.kind {
+        TransferEventKind::Started => println!("{} {} {} {}", verb, name, preposition, peer),
+        TransferEventKind::Progress { done, total, rate, eta } => {
+            let percent = if *total == 0 { 100 } else { done * 100 / total };
+            let eta = eta.map(format_duration).unwrap_or_else(|| "?".to_string());
+            println!("{} {} {} {}: {}% ({}/{}) at {}, {} left",
+                verb, name, preposition, peer,
+                percent, format_size(*done), format_size(*total),
+                throttle::format_rate(*rate), eta,
+            );
+        }
+        TransferEventKind::Completed { total, elapsed, path } => {
+            let average = (*total as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+            let verb = match event.direction {
+                Direction::Down => "Downloaded",
+                Direction::Up => "Sent",
+            };
+            println!("{} {} {} {}: {} in {}, {} average",
+                verb, name, preposition, peer,
+                format_size(*total), format_duration(*elapsed), throttle::format_rate(average),
+            );
+            if let Some(path) = path {
+                println!("Saved to {:?}", path);
+            }
+        }
+        TransferEventKind::Failed(reason) => println!("Transfer {} {} {} failed: {}", name, preposition, peer, reason),
+    }
+}
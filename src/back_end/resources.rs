@@ -0,0 +1,112 @@
+use libp2p::request_response::InboundRequestId;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::progress::{ProgressMeter, TransferEvent, TransferEventKind};
+use super::throttle::Direction;
+
+// An upload whose requester hasn't asked for a chunk in this long has been paused or given up,
+// and its slot goes to someone else
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Upload {
+    // how much of the file has gone out on the wire
+    pub sent: u64,
+    pub total: u64,
+    last_active: Instant,
+    meter: ProgressMeter,
+}
+
+// A chunk that has been handed to libp2p but not sent yet
+struct InFlight {
+    peer: PeerId,
+    filename: String,
+    end: u64,
+    len: u64,
+}
+
+// Keeps track of the files we are currently sending, so a popular uploader isn't swamped.
+// Only a few files are sent at once, and the chunks waiting to go out (held back by the
+// throttle, or not yet flushed) are capped in bytes since each one sits in memory.
+pub struct UploadSlots {
+    uploads: HashMap<(PeerId, String), Upload>,
+    in_flight: HashMap<InboundRequestId, InFlight>,
+    max_uploads: usize,
+    max_bytes: u64,
+}
+
+impl UploadSlots {
+    pub fn new(max_uploads: usize, max_bytes: u64) -> Self {
+        UploadSlots { uploads: HashMap::new(), in_flight: HashMap::new(), max_uploads, max_bytes }
+    }
+
+    fn bytes_in_flight(&self) -> u64 {
+        self.in_flight.values().map(|chunk| chunk.len).sum()
+    }
+
+    // Takes a slot for a chunk of `len` bytes of a file, or says why there isn't one free.
+    // Later chunks of a file that is already being sent keep using its slot. The first
+    // chunk of a file gives back a Started event.
+    pub fn acquire(
+        &mut self,
+        peer: PeerId,
+        filename: &str,
+        request_id: InboundRequestId,
+        offset: u64,
+        len: u64,
+        total: u64,
+    ) -> Result<Option<TransferEvent>, String> {
+        self.uploads.retain(|_, upload| upload.last_active.elapsed() < UPLOAD_IDLE_TIMEOUT);
+        let key = (peer, filename.to_string());
+        let started = !self.uploads.contains_key(&key);
+        if started && self.uploads.len() >= self.max_uploads {
+            return Err(format!("already sending {} files, try again later", self.uploads.len()));
+        }
+        // a single chunk is still allowed when nothing else is waiting to go out
+        if !self.in_flight.is_empty() && self.bytes_in_flight() + len > self.max_bytes {
+            return Err("too much data being sent already, try again later".to_string());
+        }
+        self.in_flight.insert(request_id, InFlight { peer, filename: filename.to_string(), end: offset + len, len });
+        let upload = self.uploads.entry(key).or_insert_with(|| Upload {
+            sent: offset,
+            total,
+            last_active: Instant::now(),
+            meter: ProgressMeter::new(offset),
+        });
+        upload.total = total;
+        upload.last_active = Instant::now();
+        Ok(started.then(|| TransferEvent {
+            id: None,
+            direction: Direction::Up,
+            peer,
+            filename: filename.to_string(),
+            kind: TransferEventKind::Started,
+        }))
+    }
+
+    // Frees the memory of a chunk that failed to send
+    pub fn release(&mut self, request_id: &InboundRequestId) {
+        self.in_flight.remove(request_id);
+    }
+
+    // Called once a chunk has gone out, frees its memory and reports progress. Once the
+    // peer has all of the file its slot is freed too
+    pub fn sent(&mut self, request_id: &InboundRequestId) -> Option<TransferEvent> {
+        let chunk = self.in_flight.remove(request_id)?;
+        let key = (chunk.peer, chunk.filename);
+        let upload = self.uploads.get_mut(&key)?;
+        upload.sent = upload.sent.max(chunk.end);
+        let kind = if upload.sent >= upload.total {
+            let upload = self.uploads.remove(&key)?;
+            TransferEventKind::Completed { total: upload.total, elapsed: upload.meter.elapsed(), path: None }
+        } else {
+            upload.meter.progress(upload.sent, upload.total)?
+        };
+        Some(TransferEvent { id: None, direction: Direction::Up, peer: key.0, filename: key.1, kind })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &String, &Upload)> {
+        self.uploads.iter().map(|((peer, filename), upload)| (peer, filename, upload))
+    }
+}
@@ -0,0 +1,409 @@
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::downloads;
+use super::persistent_store::write_json;
+
+const INDEX_FILE: &str = "share_index.json";
+
+// A file in one of the share directories
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedFile {
+    // the share directory it is in
+    pub root: PathBuf,
+    pub size: u64,
+    // seconds since the unix epoch, if it changes the file has to be hashed again
+    pub modified: u64,
+    // sha256 of the contents in hex, None until it has been hashed
+    pub hash: Option<String>,
+    // whether peers can see a preview of it, turned off with /previews off
+    #[serde(default = "preview_default")]
+    pub preview: bool,
+}
+
+fn preview_default() -> bool {
+    true
+}
+
+impl SharedFile {
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+}
+
+// A file to hash, and what it looked like when it was queued so a stale result can be spotted
+struct HashJob {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    modified: u64,
+}
+
+pub struct Hashed {
+    name: String,
+    size: u64,
+    modified: u64,
+    hash: std::io::Result<String>,
+}
+
+// Channels the event loop listens on, kept apart from the index so it can borrow both at once
+pub struct ShareEvents {
+    pub hashed: UnboundedReceiver<Hashed>,
+    pub changes: UnboundedReceiver<notify::Result<notify::Event>>,
+}
+
+// Every file we share, by the name peers ask for it by (its path inside the share directory,
+// with / between folders). Files are hashed in the background, and the share directories are
+// watched so the index follows files being added, changed and removed. The index is saved in
+// the data directory, so files that haven't changed since the last run aren't hashed again.
+pub struct ShareIndex {
+    path: PathBuf,
+    roots: Vec<PathBuf>,
+    files: BTreeMap<String, SharedFile>,
+    // hash -> name of a file with those contents, so files asked for by hash are found without a scan
+    by_hash: HashMap<String, String>,
+    hash_jobs: UnboundedSender<HashJob>,
+    // files queued for hashing that haven't come back yet
+    hashing: usize,
+    // say when the hashing started at startup is done
+    announce: bool,
+    dirty: bool,
+    // stops watching when dropped
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl ShareIndex {
+    pub fn start(data_dir: &Path, roots: Vec<PathBuf>) -> (Self, ShareEvents) {
+        let path = data_dir.join(INDEX_FILE);
+        // what we knew last time, only the hashes of files that haven't changed are kept
+        let saved: BTreeMap<String, SharedFile> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                eprintln!("Warning: could not read the share index, rebuilding it - {}", e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+
+        let (hash_jobs, jobs) = mpsc::unbounded_channel();
+        let (hashed_tx, hashed) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || hash_worker(jobs, hashed_tx));
+
+        // the watcher reports full paths, so the roots have to be full paths too to match them up
+        let roots: Vec<PathBuf> = roots
+            .into_iter()
+            .map(|root| {
+                if let Err(e) = std::fs::create_dir_all(&root) {
+                    eprintln!("Failed to create share directory {:?}: {}", root, e);
+                }
+                root.canonicalize().unwrap_or(root)
+            })
+            .collect();
+
+        let (changes_tx, changes) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = changes_tx.send(event);
+        });
+        let watcher = match watcher {
+            Ok(mut watcher) => {
+                for root in &roots {
+                    if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+                        eprintln!("Failed to watch share directory {:?}, changes won't be noticed until restart: {}", root, e);
+                    }
+                }
+                Some(watcher)
+            }
+            Err(e) => {
+                eprintln!("Failed to watch share directories, changes won't be noticed until restart: {}", e);
+                None
+            }
+        };
+
+        let mut index = ShareIndex {
+            path,
+            roots,
+            files: BTreeMap::new(),
+            by_hash: HashMap::new(),
+            hash_jobs,
+            hashing: 0,
+            announce: false,
+            dirty: true,
+            _watcher: watcher,
+        };
+        for root in index.roots.clone() {
+            index.scan(&root, &root, &saved);
+        }
+        if index.hashing > 0 {
+            println!("Sharing {} files, hashing {} of them in the background", index.files.len(), index.hashing);
+            index.announce = true;
+        } else {
+            println!("Sharing {} files", index.files.len());
+        }
+        (index, ShareEvents { hashed, changes })
+    }
+
+    // Adds every file under `dir` to the index. Symlinks are left out so nothing outside the
+    // share directories can be reached through one
+    fn scan(&mut self, root: &Path, dir: &Path, saved: &BTreeMap<String, SharedFile>) {
+        let Ok(read) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in read.flatten() {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                self.scan(root, &entry.path(), saved);
+            } else if file_type.is_file() {
+                self.add(root, &entry.path(), saved);
+            }
+        }
+    }
+
+    fn add(&mut self, root: &Path, path: &Path, saved: &BTreeMap<String, SharedFile>) {
+        let Some(name) = share_name(root, path) else {
+            return;
+        };
+        // with more than one share directory, the first one listed wins when names clash
+        if let Some(existing) = self.files.get(&name) {
+            if existing.root != root && self.root_order(&existing.root) < self.root_order(root) {
+                return;
+            }
+        }
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return;
+        };
+        let modified = modified_secs(&metadata);
+        let mut file = SharedFile { root: root.to_path_buf(), size: metadata.len(), modified, hash: None, preview: true };
+        let previous = self.files.get(&name).or_else(|| saved.get(&name));
+        if let Some(previous) = previous {
+            // turning previews off sticks with the name, even if the file changes
+            file.preview = previous.preview;
+            let unchanged = previous.root == file.root && previous.size == file.size && previous.modified == file.modified;
+            // unchanged, and either already hashed or already waiting to be
+            if unchanged && (previous.hash.is_some() || self.files.contains_key(&name)) {
+                file.hash = previous.hash.clone();
+                self.insert_file(name, file);
+                return;
+            }
+        }
+        let job = HashJob { name: name.clone(), path: path.to_path_buf(), size: file.size, modified };
+        if self.hash_jobs.send(job).is_ok() {
+            self.hashing += 1;
+        }
+        self.insert_file(name, file);
+        self.dirty = true;
+    }
+
+    // Every change to the files goes through insert_file and remove_file, which keep by_hash in step
+    fn insert_file(&mut self, name: String, file: SharedFile) {
+        let hash = file.hash.clone();
+        if let Some(old) = self.files.insert(name.clone(), file) {
+            self.unindex(&name, old.hash);
+        }
+        if let Some(hash) = hash {
+            self.by_hash.entry(hash).or_insert(name);
+        }
+    }
+
+    fn remove_file(&mut self, name: &str) {
+        if let Some(old) = self.files.remove(name) {
+            self.unindex(name, old.hash);
+        }
+    }
+
+    // A file no longer has `hash`, another file with the same contents takes its place in by_hash
+    fn unindex(&mut self, name: &str, hash: Option<String>) {
+        let Some(hash) = hash else {
+            return;
+        };
+        if self.by_hash.get(&hash).is_some_and(|n| n == name) {
+            self.by_hash.remove(&hash);
+            let other = self.files.iter().find(|(_, file)| file.hash.as_ref() == Some(&hash)).map(|(n, _)| n.clone());
+            if let Some(other) = other {
+                self.by_hash.insert(hash, other);
+            }
+        }
+    }
+
+    fn root_order(&self, root: &Path) -> usize {
+        self.roots.iter().position(|r| r == root).unwrap_or(usize::MAX)
+    }
+
+    // Brings the index up to date after the watcher saw something change
+    pub fn changed(&mut self, event: notify::Result<notify::Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Error watching share directories: {}", e);
+                return;
+            }
+        };
+        if event.kind.is_access() {
+            return;
+        }
+        let none = BTreeMap::new();
+        for path in event.paths {
+            let Some(root) = self.roots.iter().find(|root| path.starts_with(root)).cloned() else {
+                continue;
+            };
+            let Some(name) = share_name(&root, &path) else {
+                continue;
+            };
+            match std::fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_file() => self.add(&root, &path, &none),
+                Ok(metadata) if metadata.is_dir() => self.scan(&root, &path, &none),
+                // removed or replaced with a symlink, it was either a file or a whole folder
+                _ => {
+                    let removed: Vec<String> = self.under(&name)
+                        .filter(|(_, file)| file.root == root)
+                        .map(|(n, _)| n.clone())
+                        .collect();
+                    for name in removed {
+                        self.remove_file(&name);
+                        self.dirty = true;
+                        // another share directory may have a file by the same name
+                        for other in self.roots.clone() {
+                            let candidate = other.join(&name);
+                            if std::fs::symlink_metadata(&candidate).is_ok_and(|m| m.is_file()) {
+                                self.add(&other, &candidate, &none);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Records a hash worked out in the background, unless the file changed in the meantime
+    pub fn hashed(&mut self, hashed: Hashed) {
+        self.hashing = self.hashing.saturating_sub(1);
+        if let Some(file) = self.files.get_mut(&hashed.name) {
+            if file.size == hashed.size && file.modified == hashed.modified {
+                match hashed.hash {
+                    Ok(hash) => {
+                        file.hash = Some(hash.clone());
+                        self.by_hash.entry(hash).or_insert(hashed.name.clone());
+                        self.dirty = true;
+                    }
+                    // it changed while it was queued, it will have been queued again
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => eprintln!("Warning: could not hash shared file {} - {}", hashed.name, e),
+                }
+            }
+        }
+        if self.hashing == 0 {
+            if self.announce {
+                println!("Finished hashing shared files");
+                self.announce = false;
+            }
+            self.save();
+        }
+    }
+
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match write_json(&self.path, &self.files) {
+            Ok(()) => self.dirty = false,
+            Err(e) => eprintln!("Failed to save the share index: {}", e),
+        }
+    }
+
+    // The file on disk that a peer asking for `name` gets, if we share one by that name
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        self.files.get(name).map(|file| file.path(name))
+    }
+
+    // A file with the given contents, whatever it is called
+    pub fn find_hash(&self, hash: &str) -> Option<(&String, &SharedFile)> {
+        self.files.get_key_value(self.by_hash.get(hash)?)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SharedFile> {
+        self.files.get(name)
+    }
+
+    // Turns previews on or off for a file, or every file in a folder, returning how many files that was
+    pub fn set_preview(&mut self, name: &str, preview: bool) -> usize {
+        let names: Vec<String> = self.under(name).map(|(n, _)| n.clone()).collect();
+        for name in &names {
+            if let Some(file) = self.files.get_mut(name) {
+                file.preview = preview;
+            }
+        }
+        if !names.is_empty() {
+            self.dirty = true;
+            self.save();
+        }
+        names.len()
+    }
+
+    // Every file in a folder, or the file itself if `name` is a file
+    pub fn under<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (&'a String, &'a SharedFile)> + 'a {
+        let name = name.trim_end_matches('/');
+        let prefix = format!("{}/", name);
+        // "a b" sorts between "a" and "a/b", so go through everything starting with the name
+        self.files
+            .range(name.to_string()..)
+            .take_while(move |(n, _)| n.starts_with(name))
+            .filter(move |(n, _)| *n == name || n.starts_with(&prefix))
+    }
+
+    // Files with every keyword somewhere in their name, ignoring case. No keywords gives every file
+    pub fn search<'a>(&'a self, keywords: &'a [String]) -> impl Iterator<Item = (&'a String, &'a SharedFile)> + 'a {
+        let keywords: Vec<String> = keywords.iter().map(|k| k.to_lowercase()).collect();
+        self.files.iter().filter(move |(name, _)| {
+            let name = name.to_lowercase();
+            keywords.iter().all(|k| name.contains(k.as_str()))
+        })
+    }
+
+    pub fn hashing(&self) -> usize {
+        self.hashing
+    }
+}
+
+// The name a file is shared under: its path inside the share directory, with / between folders
+fn share_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative.components().map(|c| c.as_os_str().to_str()).collect();
+    let name = parts?.join("/");
+    // leave out names the other side would refuse to save
+    super::manifest::safe_path(&name)?;
+    Some(name)
+}
+
+fn hash_worker(mut jobs: UnboundedReceiver<HashJob>, results: UnboundedSender<Hashed>) {
+    while let Some(job) = jobs.blocking_recv() {
+        // a file still being written gets queued again and again, only hash what it is now
+        let current = std::fs::symlink_metadata(&job.path).map(|m| (m.len(), modified_secs(&m)));
+        let hash = match current {
+            Ok((size, modified)) if size == job.size && modified == job.modified => {
+                downloads::hash_file(&job.path).map(|hash| hex(&hash))
+            }
+            Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "changed while queued")),
+            Err(e) => Err(e),
+        };
+        let hashed = Hashed { name: job.name, size: job.size, modified: job.modified, hash };
+        if results.send(hashed).is_err() {
+            return;
+        }
+    }
+}
+
+fn modified_secs(metadata: &std::fs::Metadata) -> u64 {
+    metadata.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
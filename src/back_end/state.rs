@@ -0,0 +1,91 @@
+use libp2p::identity::Keypair;
+use libp2p::kad::QueryId;
+use libp2p::{rendezvous, PeerId};
+use std::time::Instant;
+
+use super::blocklist::Blocklist;
+use super::compression::CompressionStats;
+use super::file_transfer::DelayedTransfer;
+use super::gossip::RateLimiter;
+use super::nickname::Contacts;
+use super::peers::PeerBook;
+use super::progress::TransferEvent;
+use super::resources::UploadSlots;
+use super::shares::ShareIndex;
+use super::throttle::{Delayed, Throttle};
+use super::transfers::Transfers;
+use super::wishlist::Wishlist;
+use super::private_message::MessageTracker;
+
+// Everything about our own node that the command handler and the event loop both need
+pub struct ChatState {
+    pub self_peer_id: PeerId,
+    pub keypair: Keypair,
+    pub nickname: String,
+    pub message_tracker: MessageTracker,
+    pub contacts: Contacts,
+    pub peers: PeerBook,
+    pub blocklist: Blocklist,
+    pub rate_limiter: RateLimiter,
+    pub upload_slots: UploadSlots,
+    pub transfers: Transfers,
+    // the files we share, and their hashes
+    pub shares: ShareIndex,
+    // what we want, and what peers on the wishlist topic have said they want
+    pub wishlist: Wishlist,
+    // upload and download rate limits, and the file chunks they are holding back
+    pub throttle: Throttle,
+    pub delayed_transfers: Delayed<DelayedTransfer>,
+    // whether file chunks may be compressed, and how much that has saved
+    pub compression: bool,
+    pub compression_stats: CompressionStats,
+    // progress of downloads and uploads, waiting to be shown
+    pub transfer_events: Vec<TransferEvent>,
+    // show which neighbour relayed each chat message, useful when debugging the mesh
+    pub show_relays: bool,
+    // the rendezvous point we register our topic with, if one was given with --rendezvous
+    pub rendezvous_point: Option<PeerId>,
+    // lets repeated discovery only return registrations we haven't seen yet
+    pub rendezvous_cookie: Option<rendezvous::Cookie>,
+    // when to register with the rendezvous point again, before the registration expires
+    pub rendezvous_renew_at: Option<tokio::time::Instant>,
+    // the relay we hold a reservation on, peers behind a NAT can reach us through it
+    pub relay_reservation: Option<PeerId>,
+    // when our nickname record was last published, it is republished before it expires
+    pub nickname_published: Option<Instant>,
+    // set once /exit has withdrawn our nickname, we exit when that finishes or the deadline passes
+    pub exit_query: Option<QueryId>,
+    pub exit_deadline: Option<tokio::time::Instant>,
+}
+
+impl ChatState {
+    pub fn new(keypair: Keypair, nickname: String, blocklist: Blocklist, upload_slots: UploadSlots, transfers: Transfers, shares: ShareIndex, wishlist: Wishlist) -> Self {
+        ChatState {
+            self_peer_id: keypair.public().to_peer_id(),
+            keypair,
+            nickname,
+            message_tracker: MessageTracker::default(),
+            contacts: Contacts::default(),
+            peers: PeerBook::default(),
+            blocklist,
+            rate_limiter: RateLimiter::default(),
+            upload_slots,
+            transfers,
+            shares,
+            wishlist,
+            throttle: Throttle::default(),
+            delayed_transfers: Delayed::default(),
+            compression: true,
+            compression_stats: CompressionStats::default(),
+            transfer_events: Vec::new(),
+            show_relays: false,
+            rendezvous_point: None,
+            rendezvous_cookie: None,
+            rendezvous_renew_at: None,
+            relay_reservation: None,
+            nickname_published: None,
+            exit_query: None,
+            exit_deadline: None,
+        }
+    }
+}
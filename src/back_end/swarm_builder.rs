@@ -1,10 +1,27 @@
 use crate::back_end::file_transfer::FileTransferBehaviourEvent;
 use crate::back_end::file_transfer::FileTransferBehaviour;
+use crate::back_end::file_transfer;
 use crate::back_end::commands;
 use crate::back_end::behaviour;
 use crate::back_end::private_message::PrivateMessageBehaviour;
 use crate::back_end::private_message::PrivateMessageBehaviourEvent;
+use crate::back_end::private_message::{DeliveryStatus, PrivateMessageRequest};
 use crate::back_end::utils;
+use crate::back_end::nickname;
+use crate::back_end::state::ChatState;
+use crate::back_end::cli::Cli;
+use crate::back_end::discovery;
+use crate::back_end::persistent_store::{self, PersistentStore};
+use crate::back_end::transport;
+use crate::back_end::blocklist::Blocklist;
+use crate::back_end::gossip::{self, RateLimit};
+use crate::back_end::resources::UploadSlots;
+use crate::back_end::throttle::Direction;
+use crate::back_end::transfers::Transfers;
+use crate::back_end::downloads::DownloadOptions;
+use crate::back_end::progress;
+use crate::back_end::shares::ShareIndex;
+use crate::back_end::wishlist::{self, Wishlist};
 
 
 use futures::StreamExt;
@@ -12,75 +29,148 @@ use libp2p::request_response;
 use libp2p::request_response::ProtocolSupport;
 use libp2p::StreamProtocol;
 use libp2p::{
-    gossipsub, mdns, noise, swarm::SwarmEvent, tcp, yamux, kad, PeerId, 
+    autonat, connection_limits, dcutr, memory_connection_limits, gossipsub, identify, mdns, noise, ping, relay, rendezvous, swarm::SwarmEvent, yamux, kad, PeerId, 
 };
-use libp2p::kad::store::MemoryStore;
+use libp2p::kad::store::RecordStore;
 use libp2p::kad::Mode;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 use std::error::Error;
-use std::path::Path;
 use std::str::FromStr;
-use libp2p::kad::QueryId;
 use std::time::Duration;
-use std::collections::HashMap;
 use tokio::{io, io::AsyncBufReadExt, select};
 
 use behaviour::{ChatBehaviour, ChatBehaviourEvent};
 
-pub async fn start_swarm_builder() -> Result<(), Box<dyn Error>> {
+pub async fn start_swarm_builder(cli: Cli) -> Result<(), Box<dyn Error>> {
+    // Inbound records are only stored once we have checked them, so nobody can forge a nickname
+    let mut kademlia_config = kad::Config::default();
+    kademlia_config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    // provider records back @nickname lookups, let the ones for old nicknames lapse as quickly as the nickname records
+    kademlia_config.set_provider_record_ttl(Some(nickname::NICKNAME_RECORD_TTL));
+    kademlia_config.set_provider_publication_interval(Some(nickname::NICKNAME_REPUBLISH_INTERVAL));
+
+    // With a swarm key we only talk to nodes that hold the same key
+    let psk = match &cli.swarm_key {
+        Some(path) => {
+            let psk = transport::load_swarm_key(path)?;
+            println!("Private network mode, swarm key fingerprint {}", psk.fingerprint());
+            Some(psk)
+        }
+        None => None,
+    };
+
     // Build and configure the libp2p swarm
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+    // Keep hold of our keypair, it is needed to sign nickname records. It is loaded from the data directory
+    // so we keep the same peer ID between runs
+    let keypair = persistent_store::load_keypair(&cli.data_dir)?;
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair.clone())
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),  // Default TCP configuration
-            noise::Config::new,      // Noise protocol configuration for encryption
-            yamux::Config::default, // Yamux configuration for multiplexing
-        )?
-        .with_quic()  // Add QUIC support
-        .with_behaviour(|key| {
+        .with_other_transport(|key| transport::build_transport(key, psk))?  // TCP and QUIC, or TCP behind the swarm key
+        .with_relay_client(noise::Config::new, yamux::Config::default)?  // Lets us listen and dial through relays
+        .with_behaviour(|key, relay_client| {
             // Create a new instance of ChatBehaviour with mDNS and GossipSub
             Ok(ChatBehaviour {
+                blocked: Default::default(),
+                limits: connection_limits::Behaviour::new(
+                    connection_limits::ConnectionLimits::default()
+                        .with_max_established(Some(cli.max_connections))
+                        .with_max_established_per_peer(Some(cli.max_connections_per_peer))
+                        .with_max_pending_incoming(Some(cli.max_pending))
+                        .with_max_pending_outgoing(Some(cli.max_pending)),
+                ),
+                memory_limits: match cli.max_memory {
+                    Some(mb) => memory_connection_limits::Behaviour::with_max_bytes(mb * 1024 * 1024),
+                    None => memory_connection_limits::Behaviour::with_max_percentage(0.9),
+                },
                 mdns: mdns::tokio::Behaviour::new(
                     mdns::Config::default(),      // Default mDNS configuration
                     key.public().to_peer_id(),    // Local peer ID
                 )?,
-                gossipsub: gossipsub::Behaviour::new(
-                    gossipsub::MessageAuthenticity::Signed(key.clone()), // Signed message authenticity
-                    gossipsub::Config::default(),  // Default GossipSub configuration
-                )?,
-                kademlia: kad::Behaviour::new(
+                gossipsub: {
+                    let mut gossipsub = gossipsub::Behaviour::new(
+                        gossipsub::MessageAuthenticity::Signed(key.clone()), // Signed message authenticity
+                        gossip::config()?,  // Messages are validated by us before being forwarded
+                    )?;
+                    // peers that keep sending us invalid messages are scored down and eventually ignored
+                    gossipsub.with_peer_score(gossip::peer_score_params(), gossip::peer_score_thresholds())?;
+                    gossipsub
+                },
+                kademlia: kad::Behaviour::with_config(
                     key.public().to_peer_id(),
-                    MemoryStore::new(key.public().to_peer_id()),
+                    PersistentStore::open(key.public().to_peer_id(), &cli.data_dir),
+                    kademlia_config,
                 ),
                 file_transfer: FileTransferBehaviour {
                     request_response: libp2p::request_response::cbor::Behaviour::new(
-                        [(StreamProtocol::new("/file-exchange/1"),
+                        [(StreamProtocol::new("/file-exchange/2"),
+                        ProtocolSupport::Full,)],
+                        // a peer can't have more than a few file requests going over one connection, and a
+                        // throttled chunk can take longer than the default 10 seconds to come back
+                        request_response::Config::default()
+                            .with_max_concurrent_streams(cli.max_uploads)
+                            .with_request_timeout(file_transfer::CHUNK_TIMEOUT),
+                    ),
+                    manifest: libp2p::request_response::cbor::Behaviour::new(
+                        [(StreamProtocol::new("/file-manifest/1"),
                         ProtocolSupport::Full,)],
                         request_response::Config::default(),
-                    )},
+                    ),
+                    preview: libp2p::request_response::cbor::Behaviour::new(
+                        [(StreamProtocol::new("/file-preview/1"),
+                        ProtocolSupport::Full,)],
+                        request_response::Config::default(),
+                    ),
+                },
                 private_message: PrivateMessageBehaviour {
                     request_response: libp2p::request_response::cbor::Behaviour::new(
                         [(StreamProtocol::new("/private-message/1"),
                         ProtocolSupport::Full,)],
                         request_response::Config::default(),
                     )},
+                rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+                rendezvous_server: cli.rendezvous_server
+                    .then(|| rendezvous::server::Behaviour::new(rendezvous::server::Config::default()))
+                    .into(),
+                identify: identify::Behaviour::new(
+                    identify::Config::new("/swap-bytes/1.0.0".to_string(), key.public())
+                        .with_agent_version(format!("swap-bytes/{}", env!("CARGO_PKG_VERSION"))),
+                ),
+                ping: ping::Behaviour::new(ping::Config::new()),
+                relay_client,
+                relay_server: cli.relay_server
+                    .then(|| relay::Behaviour::new(key.public().to_peer_id(), relay::Config::default()))
+                    .into(),
+                dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+                autonat: autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config::default()),
             })
         })?
         .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))  // Configure idle connection timeout
         .build();
 
     //Let user select nickname
-    let self_peer_id = swarm.local_peer_id().clone();
+    let self_peer_id = *swarm.local_peer_id();
     let mut stdin = io::BufReader::new(io::stdin()).lines();
     println!("Enter your nickname");
     let mut nickname = stdin.next_line().await.unwrap().unwrap();
     if nickname.is_empty() {
         nickname = self_peer_id.to_string();
     }
-    let mut has_set_name = false;
-    let mut chat_pending_queries: HashMap<QueryId, (PeerId, String)> = HashMap::new();
-    let mut private_chat_pending_queries: HashMap<QueryId, (PeerId, String)> = HashMap::new();
+    let download_options = DownloadOptions {
+        dir: cli.download_dir.clone(),
+        layout: cli.download_layout,
+        collision: cli.on_collision,
+    };
+    let upload_slots = UploadSlots::new(cli.max_uploads, cli.upload_buffer * 1024 * 1024);
+    let (shares, mut share_events) = ShareIndex::start(&cli.data_dir, cli.shares.clone());
+    let (transfers, mut finished_downloads) = Transfers::new(cli.max_downloads, download_options);
+    let mut state = ChatState::new(keypair, nickname, Blocklist::load(&cli.data_dir), upload_slots, transfers, shares, Wishlist::load(&cli.data_dir));
+    state.throttle.set_limit(Direction::Up, None, cli.upload_limit);
+    state.throttle.set_limit(Direction::Down, None, cli.download_limit);
+    state.compression = !cli.no_compression;
+    for peer_id in state.blocklist.iter() {
+        swarm.behaviour_mut().blocked.block_peer(*peer_id);
+        swarm.behaviour_mut().gossipsub.blacklist_peer(peer_id);
+        swarm.behaviour_mut().kademlia.remove_peer(peer_id);
+    }
     
 
     loop {
@@ -102,24 +192,140 @@ pub async fn start_swarm_builder() -> Result<(), Box<dyn Error>> {
         swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
         break; // Exit the loop once a valid topic is chosen
     }
+    // wishlists are shared across all chat topics
+    swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(wishlist::TOPIC))?;
     
     
     swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
     // Listen on specified TCP and UDP ports
     swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+    if psk.is_none() {
+        swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+    }
+    // peers we knew about last time we ran, so we can rejoin without waiting on mDNS
+    let saved_peers = persistent_store::load_routing_table(&cli.data_dir);
+    for (peer_id, addresses) in &saved_peers {
+        for addr in addresses {
+            swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
+        }
+    }
+    if !saved_peers.is_empty() {
+        println!("Loaded {} peers from the saved routing table", saved_peers.len());
+        let _ = swarm.behaviour_mut().kademlia.bootstrap();
+    }
+    // mDNS only finds peers on the local network, bootstrap peers let us reach further
+    discovery::bootstrap(&mut swarm, &cli.bootstrap);
+    if let Some(addr) = cli.rendezvous.clone() {
+        match discovery::peer_id_from_addr(&addr) {
+            Some(point) => {
+                state.rendezvous_point = Some(point);
+                discovery::dial_address(&mut swarm, addr)?;
+            }
+            None => eprintln!("Rendezvous address {addr} has no /p2p/<peer_id>, ignoring it"),
+        }
+    }
+    if let Some(addr) = cli.relay.clone() {
+        match discovery::peer_id_from_addr(&addr) {
+            Some(relay_peer) => {
+                // the relay is publicly reachable, so it is also a good judge of whether we are
+                swarm.behaviour_mut().autonat.add_server(relay_peer, Some(addr.clone()));
+                discovery::dial_address(&mut swarm, addr.clone())?;
+                swarm.listen_on(addr.with(libp2p::multiaddr::Protocol::P2pCircuit))?;
+            }
+            None => eprintln!("Relay address {addr} has no /p2p/<peer_id>, ignoring it"),
+        }
+    }
+    for address in &cli.external_addresses {
+        swarm.add_external_address(address.clone());
+    }
+    if cli.relay_server {
+        println!("Relaying connections for other nodes");
+    }
+    if cli.rendezvous_server {
+        println!("Running as a rendezvous point, other nodes can use --rendezvous <address>/p2p/{}", state.self_peer_id);
+    }
+
+    let mut nickname_refresh = tokio::time::interval(nickname::NICKNAME_REFRESH_INTERVAL);
+    let mut rendezvous_discover = tokio::time::interval(discovery::RENDEZVOUS_DISCOVER_INTERVAL);
+    let mut save_store = tokio::time::interval(persistent_store::SAVE_INTERVAL);
+    let mut nickname_republish = tokio::time::interval(nickname::NICKNAME_REPUBLISH_INTERVAL);
+    let mut wishlist_publish = tokio::time::interval(wishlist::PUBLISH_INTERVAL);
 
     // Start the event handler
     println!("Enter chat messages one line at a time");
     loop {
         select! {
+            // Keep the nicknames of peers we are connected to fresh in the background
+            _ = nickname_refresh.tick() => {
+                let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+                for peer_id in peers {
+                    nickname::refresh_nickname(&mut swarm.behaviour_mut().kademlia, &mut state.contacts, peer_id);
+                }
+            }
+            _ = nickname_republish.tick(), if swarm.connected_peers().next().is_some() => {
+                nickname::republish_nickname(&mut swarm.behaviour_mut().kademlia, &mut state, nickname::NICKNAME_REPUBLISH_INTERVAL / 2);
+            }
+            _ = tokio::time::sleep_until(state.exit_deadline.unwrap_or_else(tokio::time::Instant::now)), if state.exit_deadline.is_some() => {
+                commands::exit(&mut swarm);
+            }
+            _ = tokio::time::sleep_until(state.delayed_transfers.next_deadline().unwrap_or_else(tokio::time::Instant::now)), if state.delayed_transfers.next_deadline().is_some() => {
+                swarm.behaviour_mut().file_transfer.send_delayed(&mut state);
+            }
+            _ = tokio::signal::ctrl_c() => {
+                commands::begin_exit(&mut swarm, &mut state);
+            }
+            _ = save_store.tick() => {
+                if let Err(e) = persistent_store::save(&mut swarm.behaviour_mut().kademlia) {
+                    eprintln!("Failed to save DHT records: {e}");
+                }
+                state.shares.save();
+            }
+            // keep the share index in step with the share directories
+            Some(hashed) = share_events.hashed.recv() => {
+                state.shares.hashed(hashed);
+            }
+            Some(change) = share_events.changes.recv() => {
+                state.shares.changed(change);
+            }
+            // a completed download has been checked and moved into place
+            Some(finishing) = finished_downloads.recv() => {
+                swarm.behaviour_mut().file_transfer.handle_finished(finishing, &mut state);
+            }
+            // send our wishlist again for peers who joined since, and offer anything newly shared
+            _ = wishlist_publish.tick() => {
+                wishlist::broadcast(&mut state);
+            }
+            _ = tokio::time::sleep_until(state.wishlist.broadcast_at().unwrap_or_else(tokio::time::Instant::now)), if state.wishlist.broadcast_at().is_some() => {
+                wishlist::broadcast(&mut state);
+            }
+            _ = tokio::time::sleep_until(state.wishlist.next_send().unwrap_or_else(tokio::time::Instant::now)), if state.wishlist.next_send().is_some() => {
+                wishlist::send_next(&mut swarm, &mut state);
+            }
+            _ = tokio::time::sleep_until(state.rendezvous_renew_at.unwrap_or_else(tokio::time::Instant::now)), if state.rendezvous_renew_at.is_some() => {
+                discovery::rendezvous_renew(&mut swarm, &mut state);
+            }
+            _ = rendezvous_discover.tick() => {
+                discovery::rendezvous_discover(&mut swarm, &state);
+            }
             Ok(Some(mut line)) = stdin.next_line() =>  {
+                // the user is at the terminal, so has seen the private messages printed since they last typed
+                for (peer, id) in state.message_tracker.take_unread() {
+                    let receipt = PrivateMessageRequest::Receipt { id, status: DeliveryStatus::Read };
+                    swarm.behaviour_mut().private_message.send_request(peer, receipt)?;
+                }
                 if line.starts_with("/") {
-                    commands::handle_command(line, &mut swarm, self_peer_id)?;
+                    commands::handle_command(line, &mut swarm, &mut state)?;
                 } else {
-                    let current_topic: Vec<_> = swarm.behaviour_mut().gossipsub.topics().collect();
-                    let topic = gossipsub::IdentTopic::new(current_topic[0].to_string());
+                    let Some(current_topic) = discovery::current_topic(&swarm) else {
+                        println!("Join a topic with /join before chatting");
+                        continue;
+                    };
+                    let topic = gossipsub::IdentTopic::new(current_topic);
                     line = format!("[{topic}]: {line}");
+                    if line.len() > gossip::MAX_MESSAGE_SIZE {
+                        println!("Message too long, the limit is {} bytes", gossip::MAX_MESSAGE_SIZE);
+                        continue;
+                    }
                     // Publish the message to the chat topic
                     if let Err(err) = swarm.behaviour_mut().gossipsub.publish(topic, line.as_bytes()) {
                         println!("Error publishing: {:?}", err);
@@ -130,31 +336,118 @@ pub async fn start_swarm_builder() -> Result<(), Box<dyn Error>> {
             event = swarm.select_next_some() => match event {
                 SwarmEvent::NewListenAddr { address, ..} => {
                     println!("Your node is listening on {address}");
+                    // a relayed address works as soon as the relay has given us a reservation. Other listen
+                    // addresses may be 0.0.0.0, loopback or behind a NAT, those are only advertised once
+                    // identify and AutoNAT have confirmed peers can actually reach them
+                    if address.iter().any(|p| p == libp2p::multiaddr::Protocol::P2pCircuit) {
+                        swarm.add_external_address(address);
+                    }
+                }
+                SwarmEvent::ExternalAddrConfirmed { address } => {
+                    println!("Confirmed external address {address}");
+                    // rendezvous registrations need addresses others can reach us on
+                    discovery::rendezvous_renew(&mut swarm, &mut state);
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } if Some(peer_id) == state.rendezvous_point && num_established.get() == 1 => {
+                    if let Some(topic) = discovery::current_topic(&swarm) {
+                        discovery::rendezvous_register(&mut swarm, &mut state, &topic);
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { is_new_peer: true, .. })) => {
+                    // a new peer joining the DHT is a chance to get our nickname onto more of the network
+                    nickname::republish_nickname(&mut swarm.behaviour_mut().kademlia, &mut state, nickname::NICKNAME_REPUBLISH_COOLDOWN);
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    state.peers.disconnected(&peer_id);
+                    if state.relay_reservation == Some(peer_id) {
+                        println!("Lost connection to relay {peer_id}, peers behind a NAT can no longer reach you");
+                        state.relay_reservation = None;
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                    // peers found outside of mDNS only reach Kademlia through the addresses they tell us
+                    if info.protocols.contains(&kad::PROTOCOL_NAME) {
+                        for addr in &info.listen_addrs {
+                            swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+                        }
+                    }
+                    state.peers.identified(peer_id, &info);
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Ping(ping::Event { peer, result: Ok(rtt), .. })) => {
+                    state.peers.pinged(peer, rtt);
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => {
+                    match new {
+                        autonat::NatStatus::Public(addr) => println!("NAT status: publicly reachable at {addr}"),
+                        autonat::NatStatus::Private => println!("NAT status: behind a NAT, use --relay so peers can reach you"),
+                        autonat::NatStatus::Unknown => println!("NAT status: unknown"),
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. })) => {
+                    if !renewal {
+                        println!("Reserved a slot on relay {relay_peer_id}, peers can now reach you through it");
+                    }
+                    state.relay_reservation = Some(relay_peer_id);
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::RelayClient(relay::client::Event::InboundCircuitEstablished { src_peer_id, .. })) => {
+                    println!("{src_peer_id} connected to us through the relay");
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::RelayServer(relay::Event::ReservationReqAccepted { src_peer_id, renewed: false })) => {
+                    println!("Relaying for {src_peer_id}");
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                    match result {
+                        Ok(_) => println!("Hole punched a direct connection to {remote_peer_id}"),
+                        Err(e) => eprintln!("Failed to hole punch a direct connection to {remote_peer_id}: {e}"),
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Rendezvous(rendezvous::client::Event::Registered { namespace, ttl, .. })) => {
+                    println!("Registered with rendezvous point in {namespace} for {ttl} seconds");
+                    // renew at half time, so a missed renewal still leaves time for another go
+                    state.rendezvous_renew_at = Some(tokio::time::Instant::now() + Duration::from_secs(ttl / 2));
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Rendezvous(rendezvous::client::Event::RegisterFailed { namespace, error, .. })) => {
+                    eprintln!("Failed to register with rendezvous point in {namespace}: {error:?}");
+                    state.rendezvous_renew_at = Some(tokio::time::Instant::now() + discovery::RENDEZVOUS_RETRY_DELAY);
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered { registrations, cookie, .. })) => {
+                    state.rendezvous_cookie = Some(cookie);
+                    for registration in registrations {
+                        let peer_id = registration.record.peer_id();
+                        if peer_id == state.self_peer_id || swarm.is_connected(&peer_id) || state.blocklist.contains(&peer_id) {
+                            continue;
+                        }
+                        println!("Rendezvous discovered peer: {peer_id} in {}", registration.namespace);
+                        for address in registration.record.addresses() {
+                            let address = address.clone().with(libp2p::multiaddr::Protocol::P2p(peer_id));
+                            if let Err(e) = discovery::dial_address(&mut swarm, address) {
+                                eprintln!("Failed to dial {peer_id}: {e}");
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Rendezvous(rendezvous::client::Event::DiscoverFailed { error, .. })) => {
+                    eprintln!("Rendezvous discovery failed: {error:?}");
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::RendezvousServer(rendezvous::server::Event::PeerRegistered { peer, registration })) => {
+                    println!("Peer {peer} registered in {}", registration.namespace);
+                }
+                SwarmEvent::IncomingConnectionError { send_back_addr, error: libp2p::swarm::ListenError::Denied { cause }, .. } => {
+                    // usually a connection limit or a blocked peer
+                    eprintln!("Refused connection from {send_back_addr}: {cause}");
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                    eprintln!("Failed to connect to {}: {error}", peer_id.map(|p| p.to_string()).unwrap_or_else(|| "peer".to_string()));
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                     for (peer_id, multiaddr) in list {
+                        if state.blocklist.contains(&peer_id) {
+                            continue;
+                        }
                         println!("mDNS discovered peer: {peer_id}, listening on {multiaddr}");
                         // Add discovered peers to GossipSub
                         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
                         swarm.behaviour_mut().kademlia.add_address(&peer_id, multiaddr);
-                        //if user has not set nickname
-                        if !has_set_name {
-                            let nickname_record = kad::Record {
-                                key: kad::RecordKey::new(&self_peer_id.to_string()),
-                                value: nickname.as_bytes().to_vec(),
-                                publisher: None,
-                                expires: None,
-                            };
-                            match swarm.behaviour_mut().kademlia.put_record(nickname_record, kad::Quorum::One) {
-                                Ok(_) => {
-                                    // If the record is stored successfully, set has_set_name to true
-                                    has_set_name = true;
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to store record: {:?}", e);
-                                }
-                            }
-                        }
                     }
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
@@ -165,47 +458,81 @@ pub async fn start_swarm_builder() -> Result<(), Box<dyn Error>> {
                     }
                 }
                 SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
-                    message, ..
+                    propagation_source,
+                    message_id,
+                    message,
                 })) => {
-                    {
-                        if let Ok(msg) = String::from_utf8(message.data.clone()) {
-                            // Start a query to get the nickname from the DHT
-                            let query_id = swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&peer_id.to_string()));
-                            //get topic of message
-
-                            chat_pending_queries.insert(query_id, (peer_id.clone(), msg));
+                    // propagation_source is only the neighbour that relayed the message to us, the author is
+                    // message.source, which gossipsub has already checked against the message signature
+                    let Some(author) = message.source else {
+                        eprintln!("Dropped unsigned message relayed by {propagation_source}");
+                        gossip::report(&mut swarm.behaviour_mut().gossipsub, &message_id, &propagation_source, gossipsub::MessageAcceptance::Reject);
+                        continue;
+                    };
+                    // gossipsub already drops messages from blacklisted peers, this catches anything that slipped through.
+                    // Blocking is our own choice, so the message is ignored rather than held against the sender
+                    if state.blocklist.contains(&author) {
+                        gossip::report(&mut swarm.behaviour_mut().gossipsub, &message_id, &propagation_source, gossipsub::MessageAcceptance::Ignore);
+                        continue;
+                    }
+                    let received = match gossip::check(&message.topic, &message.data) {
+                        Ok(received) => received,
+                        Err(reason) => {
+                            eprintln!("Dropped message from {}: {reason}", state.contacts.display_name(&author));
+                            gossip::report(&mut swarm.behaviour_mut().gossipsub, &message_id, &propagation_source, gossipsub::MessageAcceptance::Reject);
+                            continue;
+                        }
+                    };
+                    // chat and wishlists are limited separately, so offers never eat into someone's chat
+                    let rate_limiter = match received {
+                        gossip::Received::Chat(_) => &mut state.rate_limiter,
+                        gossip::Received::Wishlist(_) => &mut state.wishlist.rate_limiter,
+                    };
+                    match rate_limiter.check(author) {
+                        RateLimit::Allowed => {}
+                        limit => {
+                            if let RateLimit::Exceeded = limit {
+                                eprintln!("{} is sending messages too fast, dropping them for now", state.contacts.display_name(&author));
+                            }
+                            // the message itself is fine, so only the flooder is penalised, never the relays passing it on
+                            let acceptance = match author == propagation_source {
+                                true => gossipsub::MessageAcceptance::Reject,
+                                false => gossipsub::MessageAcceptance::Ignore,
+                            };
+                            gossip::report(&mut swarm.behaviour_mut().gossipsub, &message_id, &propagation_source, acceptance);
+                            continue;
                         }
                     }
+                    gossip::report(&mut swarm.behaviour_mut().gossipsub, &message_id, &propagation_source, gossipsub::MessageAcceptance::Accept);
+                    let msg = match received {
+                        gossip::Received::Chat(msg) => msg,
+                        gossip::Received::Wishlist(wishes) => {
+                            wishlist::handle_message(&mut state, author, wishes);
+                            nickname::refresh_nickname(&mut swarm.behaviour_mut().kademlia, &mut state.contacts, author);
+                            continue;
+                        }
+                    };
+                    // Show the message straight away, the nickname is fetched in the background if we don't have it
+                    if state.show_relays && author != propagation_source {
+                        println!("{} {msg} (relayed by {})", state.contacts.display_name(&author), state.contacts.display_name(&propagation_source));
+                    } else {
+                        println!("{} {msg}", state.contacts.display_name(&author));
+                    }
+                    nickname::refresh_nickname(&mut swarm.behaviour_mut().kademlia, &mut state.contacts, author);
                 }
-                SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {id, result, ..})) => {
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {id, result, step, ..})) => {
                     match result {
                         // Get record return result
                         kad::QueryResult::GetRecord(Ok(
                             kad::GetRecordOk::FoundRecord(kad::PeerRecord {
-                                record: kad::Record { value, ..},
+                                record,
                                 ..
                             })
                         )) => {
-                            if let Some((peer_id, msg)) = chat_pending_queries.remove(&id) {
-                                match std::str::from_utf8(&value) {
-                                    Ok(nickname) => {
-                                        println!("{nickname} {msg}");
-                                    }
-                                    Err(_) => {
-                                        println!("Failed to decode nickname for peer {peer_id}, but received: {msg}");
-                                    }
-                                }
-                            }
-                            if let Some((peer_id, msg)) = private_chat_pending_queries.remove(&id) {
-                                match std::str::from_utf8(&value) {
-                                    Ok(nickname) => {
-                                        println!("{} [Private]: {}",nickname, msg);
-                                    }
-                                    Err(_) => {
-                                        println!("Failed to decode nickname for peer {peer_id}, but received: {msg}");
-                                    }
-                                }
+                            // forged records are dropped, so the peer keeps showing as its peer id
+                            match nickname::verify_nickname_record(&record) {
+                                Some(verified) => state.contacts.insert(verified),
+                                None => eprintln!("Dropped nickname record with an invalid signature"),
                             }
                         }
 
@@ -213,98 +540,219 @@ pub async fn start_swarm_builder() -> Result<(), Box<dyn Error>> {
                         kad::QueryResult::GetRecord(Err(err)) => {
                             println!("Failed to get record {err:?}");
                         }
+                        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+                            state.contacts.lookup_progress(&id, providers);
+                        }
+                        kad::QueryResult::GetProviders(_) => {}
+                        _ if state.exit_query == Some(id) => {
+                            commands::exit(&mut swarm);
+                        }
                         kad::QueryResult::PutRecord(Ok(kad::PutRecordOk {key })) => {
                             println!("Successfully put record {:?}", std::str::from_utf8(key.as_ref()).unwrap());
                         }
                         kad::QueryResult::PutRecord(Err(err)) => {
                             println!("Failed to put record {err:?}");
                         }
+                        kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { num_remaining: 0, .. })) => {
+                            println!("Kademlia bootstrap finished");
+                        }
+                        kad::QueryResult::Bootstrap(Err(err)) => {
+                            eprintln!("Kademlia bootstrap failed {err:?}");
+                        }
                         _ => {}
                     }
+                    if step.last {
+                        // a nickname lookup started by a command has finished, run the command again
+                        if let Some(lookup) = nickname::query_finished(&mut swarm.behaviour_mut().kademlia, &mut state.contacts, &id) {
+                            if lookup.found.is_empty() {
+                                println!("No peer found with nickname {}", lookup.nickname);
+                            } else {
+                                commands::handle_command(lookup.line, &mut swarm, &mut state)?;
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(kad::Event::InboundRequest {
+                    request: kad::InboundRequest::PutRecord { source, record: Some(record), .. },
+                })) => {
+                    // only store nickname records that were signed by the peer they belong to, never let an
+                    // old one replace a newer one we already have, and keep them for as long as the signed expiry says
+                    match nickname::verify_nickname_record(&record) {
+                        Some(verified) => {
+                            let store = swarm.behaviour_mut().kademlia.store_mut();
+                            let keep_until = nickname::keep_until(&verified);
+                            if nickname::is_outdated(&verified, store.get(&record.key).as_deref()) {
+                                eprintln!("Dropped outdated nickname record from {source}");
+                            } else if keep_until.is_none() {
+                                eprintln!("Dropped expired nickname record from {source}");
+                            } else if let Err(e) = store.put(kad::Record { expires: keep_until, ..record }) {
+                                eprintln!("Failed to store record from {source}: {e:?}");
+                            }
+                        }
+                        None => eprintln!("Dropped forged nickname record from {source}"),
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::Kademlia(kad::Event::InboundRequest {
+                    request: kad::InboundRequest::AddProvider { record: Some(record) },
+                })) => {
+                    if let Err(e) = swarm.behaviour_mut().kademlia.store_mut().add_provider(record) {
+                        eprintln!("Failed to store provider record: {e:?}");
+                    }
                 }
-                SwarmEvent::Behaviour(ChatBehaviourEvent::PrivateMessage(private_message_event)) => match private_message_event {
-                    PrivateMessageBehaviourEvent::RequestResponse(request_response::Event::Message {
+                SwarmEvent::Behaviour(ChatBehaviourEvent::PrivateMessage(PrivateMessageBehaviourEvent::RequestResponse(request_response::Event::Message {
+                    peer,
+                    message,
+                }))) => match message {
+                    request_response::Message::Request {
+                        request: PrivateMessageRequest::Message(_), ..
+                    } if state.blocklist.contains(&peer) => {
+                        // dropping the channel without answering, the sender just sees the request fail
+                    }
+                    request_response::Message::Request {
+                        request: PrivateMessageRequest::Message(request), channel, ..
+                    } => {
+                        // only accept messages where the claimed sender is the peer that actually sent it
+                        let status = match PeerId::from_str(&request.sender) {
+                            Ok(sender) if sender == peer => {
+                                println!("{} [Private]: {}", state.contacts.display_name(&peer), request.message);
+                                nickname::refresh_nickname(&mut swarm.behaviour_mut().kademlia, &mut state.contacts, peer);
+                                DeliveryStatus::Delivered
+                            }
+                            _ => {
+                                eprintln!("Rejected private message from {peer}: sender does not match connection");
+                                DeliveryStatus::Rejected("sender does not match connection".to_string())
+                            }
+                        };
+                        if status == DeliveryStatus::Delivered {
+                            // the read receipt goes once the user has had a chance to see it
+                            state.message_tracker.received(peer, request.id);
+                        }
+                        PrivateMessageBehaviour::handle_request(&mut swarm.behaviour_mut().private_message, channel, request.id, status).await?;
+                    }
+                    request_response::Message::Request {
+                        request: PrivateMessageRequest::Receipt { id, status }, channel, ..
+                    } => {
+                        if state.message_tracker.receipt(peer, id, status.clone()).is_some() {
+                            println!("Private message #{id} to {peer}: {status}");
+                        }
+                        PrivateMessageBehaviour::handle_request(&mut swarm.behaviour_mut().private_message, channel, id, status).await?;
+                    }
+                    request_response::Message::Response {
+                        request_id, response,
+                    } => {
+                        if let Some(id) = state.message_tracker.acknowledge(request_id, response) {
+                            if let Some(sent) = state.message_tracker.get(id) {
+                                println!("Private message #{id} to {peer}: {}", sent.status);
+                            }
+                        }
+                    }
+                },
+                SwarmEvent::Behaviour(ChatBehaviourEvent::PrivateMessage(PrivateMessageBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure {
+                    peer, request_id, error,
+                }))) => {
+                    if let Some(id) = state.message_tracker.fail(request_id, error.to_string()) {
+                        println!("Private message #{id} to {peer}: failed ({error})");
+                    }
+                }
+                SwarmEvent::Behaviour(ChatBehaviourEvent::FileTransfer(file_transfer_event)) => match file_transfer_event {
+
+                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::Message {
+                        peer,
                         message,
-                        ..
                     }) => match message {
                         request_response::Message::Request {
-                            request, channel, ..
+                            request, ..
+                        } if state.blocklist.contains(&peer) => {
+                            println!("Ignored request for file {} from blocked peer {}", request.filename, peer);
+                        }
+                        request_response::Message::Request {
+                            request_id, request, channel,
                         } => {
-                            let peer_id_from_str = PeerId::from_str(&request.sender).unwrap();
-                            let query_id = swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&request.sender));
-                            private_chat_pending_queries.insert(query_id, (peer_id_from_str, request.message.clone()));
-                            
-                            PrivateMessageBehaviour::handle_request(&mut swarm.behaviour_mut().private_message, channel).await?;
+                            // a request has been received
+                            swarm.behaviour_mut().file_transfer.handle_request(peer, request, request_id, channel, &mut state).await;
                         }
                         request_response::Message::Response {
-                            response, ..
+                            request_id, response,
                         } => {
-                            let message = response.0;
-                            println!("{message}");
+                            swarm.behaviour_mut().file_transfer.handle_response(request_id, response, &mut state).await;
                         }
                     },
-                    _ => {} 
-                } 
-                SwarmEvent::Behaviour(ChatBehaviourEvent::FileTransfer(file_transfer_event)) => match file_transfer_event {
 
-                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::Message {
+                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure { request_id, error, .. }) => {
+                        swarm.behaviour_mut().file_transfer.handle_failure(request_id, error.to_string(), &mut state);
+                    },
+
+                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::InboundFailure { peer, request_id, error }) => {
+                        state.upload_slots.release(&request_id);
+                        println!("Failed to process request from peer {:?}: {:?}", peer, error);
+                    },
+                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::ResponseSent { request_id, .. }) => {
+                        state.transfer_events.extend(state.upload_slots.sent(&request_id));
+                    },
+
+                    FileTransferBehaviourEvent::Manifest(request_response::Event::Message {
+                        peer,
                         message,
-                        ..
                     }) => match message {
+                        request_response::Message::Request {
+                            request, ..
+                        } if state.blocklist.contains(&peer) => {
+                            println!("Ignored request for files {} from blocked peer {}", request.paths.join(", "), peer);
+                        }
                         request_response::Message::Request {
                             request, channel, ..
                         } => {
-                            // a request has been received
-                            FileTransferBehaviour::handle_request(&mut swarm.behaviour_mut().file_transfer, request, channel).await?;
-                           }
+                            swarm.behaviour_mut().file_transfer.handle_manifest_request(request, channel, &state);
+                        }
                         request_response::Message::Response {
-                            response, ..
+                            request_id, response,
                         } => {
-                            // clean the name to stop attacks, saw this on some examples dont really know what it means
-                            let sanitized_name = response.filename.replace(&['/', '\\'][..], "_"); 
-                            let filename = format!("downloads/{}", sanitized_name);
-                        
-                            // create the downloads directory if it doesn't exist
-                            if let Some(parent) = Path::new(&filename).parent() {
-                                tokio::fs::create_dir_all(parent).await?;
-                            }
-                            //check if responsedata is empy
-                            if !response.data.is_empty() {
-                                match File::create(&filename).await {
-                                    Ok(mut file) => {
-                                        if let Err(e) = file.write_all(&response.data).await {
-                                            eprintln!("Failed to write data to file: {}", e);
-                                        } else {
-                                            println!("File saved to {:?}", filename);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to create file: {}", e);
-                                    }
-                                }
-                            }
-                            else {
-                                println!("File does not exist");
-                            }                            
+                            swarm.behaviour_mut().file_transfer.handle_manifest(request_id, response, &mut state);
                         }
-                    }, 
-        
-                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::OutboundFailure { peer, .. }) => {
-                        println!("Failed to send request to peer {:?}: This usually means incorrect filename, or the user doesnt have the file you requested", peer );
-                    }, 
-        
-                    FileTransferBehaviourEvent::RequestResponse(request_response::Event::InboundFailure { peer, error, .. }) => {
-                        println!("Failed to process request from peer {:?}: {:?}", peer, error);
-                    }, 
-                    _ => {}            
+                    },
+
+                    FileTransferBehaviourEvent::Manifest(request_response::Event::OutboundFailure { request_id, error, .. }) => {
+                        swarm.behaviour_mut().file_transfer.handle_manifest_failure(request_id, error.to_string(), &mut state);
+                    },
+
+                    FileTransferBehaviourEvent::Manifest(_) => {}
+
+                    FileTransferBehaviourEvent::Preview(request_response::Event::Message {
+                        peer,
+                        message,
+                    }) => match message {
+                        request_response::Message::Request {
+                            request, ..
+                        } if state.blocklist.contains(&peer) => {
+                            println!("Ignored request for a preview of {:?} from blocked peer {}", request.name, peer);
+                        }
+                        request_response::Message::Request {
+                            request, channel, ..
+                        } => {
+                            swarm.behaviour_mut().file_transfer.handle_preview_request(request, channel, &state);
+                        }
+                        request_response::Message::Response {
+                            response, ..
+                        } => match response.check() {
+                            Ok(()) => response.print(&state.contacts.display_name(&peer)),
+                            Err(reason) => eprintln!("Dropped preview from {}: {reason}", state.contacts.display_name(&peer)),
+                        },
+                    },
+
+                    FileTransferBehaviourEvent::Preview(request_response::Event::OutboundFailure { peer, error, .. }) => {
+                        println!("Failed to get a preview from {}: {}", state.contacts.display_name(&peer), error);
+                    },
+
+                    FileTransferBehaviourEvent::Preview(_) => {}
                 },
         
                 
                 _ => {}
             }
         }
+        // show how downloads and uploads are getting on, whichever branch moved them along
+        for event in state.transfer_events.drain(..) {
+            progress::print_event(&event, &state.contacts);
+        }
     }
 }
-
-
-
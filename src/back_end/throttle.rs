@@ -0,0 +1,159 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::utils::format_size;
+
+// The longest a chunk is held back for. Under a low limit, or with several uploads sharing one, chunks are
+// made smaller instead, so a response never waits long enough for the request to time out
+pub const MAX_DELAY: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Up => write!(f, "up"),
+            Direction::Down => write!(f, "down"),
+        }
+    }
+}
+
+// Token bucket allowing `rate` bytes a second, with up to a second's worth saved up for bursts.
+// Bytes are always handed out, going into debt if need be, and the debt is how long to wait.
+struct Bucket {
+    rate: u64,
+    balance: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket { rate, balance: rate as f64, last: Instant::now() }
+    }
+
+    // How many bytes could be taken now without having to wait more than `max_delay`
+    fn available(&self, max_delay: Duration) -> f64 {
+        let refill = self.last.elapsed().as_secs_f64() * self.rate as f64;
+        (self.balance + refill).min(self.rate as f64) + max_delay.as_secs_f64() * self.rate as f64
+    }
+
+    fn reserve(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.balance = (self.balance + refill).min(self.rate as f64);
+        self.last = now;
+        self.balance -= bytes as f64;
+        if self.balance >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.balance / self.rate as f64)
+        }
+    }
+}
+
+// Upload and download rate limits, for everything together and for single peers, set with /limit
+#[derive(Default)]
+pub struct Throttle {
+    global: HashMap<Direction, Bucket>,
+    peers: HashMap<(PeerId, Direction), Bucket>,
+}
+
+impl Throttle {
+    // Sets a limit in bytes a second, None removes it. Without a peer the limit covers all peers together
+    pub fn set_limit(&mut self, direction: Direction, peer: Option<PeerId>, rate: Option<u64>) {
+        match (peer, rate) {
+            (None, Some(rate)) => { self.global.insert(direction, Bucket::new(rate)); }
+            (None, None) => { self.global.remove(&direction); }
+            (Some(peer), Some(rate)) => { self.peers.insert((peer, direction), Bucket::new(rate)); }
+            (Some(peer), None) => { self.peers.remove(&(peer, direction)); }
+        }
+    }
+
+    // Takes `bytes` out of the global and the peer's allowance, returning how long to wait
+    // before they go on the wire so both limits are kept
+    pub fn reserve(&mut self, direction: Direction, peer: PeerId, bytes: u64) -> Duration {
+        let global = self.global.get_mut(&direction).map(|bucket| bucket.reserve(bytes)).unwrap_or_default();
+        let peer = self.peers.get_mut(&(peer, direction)).map(|bucket| bucket.reserve(bytes)).unwrap_or_default();
+        global.max(peer)
+    }
+
+    // The biggest chunk, up to `max`, that can be sent without waiting more than MAX_DELAY for either limit.
+    // Always at least a byte so the transfer keeps moving
+    pub fn chunk_size(&self, direction: Direction, peer: PeerId, max: u64) -> u64 {
+        let global = self.global.get(&direction).map(|bucket| bucket.available(MAX_DELAY));
+        let peer = self.peers.get(&(peer, direction)).map(|bucket| bucket.available(MAX_DELAY));
+        let available = global.into_iter().chain(peer).fold(max as f64, f64::min);
+        (available as u64).clamp(1, max)
+    }
+
+    pub fn global_limit(&self, direction: Direction) -> Option<u64> {
+        self.global.get(&direction).map(|bucket| bucket.rate)
+    }
+
+    pub fn peer_limits(&self) -> impl Iterator<Item = (&PeerId, Direction, u64)> {
+        self.peers.iter().map(|((peer, direction), bucket)| (peer, *direction, bucket.rate))
+    }
+}
+
+// Parses a rate like 500KB/s, 2MB/s or 4096 (bytes a second). "off" gives None, meaning no limit
+pub fn parse_rate(input: &str) -> Result<Option<u64>, String> {
+    let lower = input.to_ascii_lowercase();
+    if lower == "off" {
+        return Ok(None);
+    }
+    let amount = lower.strip_suffix("/s").unwrap_or(&lower);
+    let split = amount.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(amount.len());
+    let (number, unit) = amount.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1.0,
+        "kb" | "k" | "kib" => 1024.0,
+        "mb" | "m" | "mib" => 1024.0 * 1024.0,
+        "gb" | "g" | "gib" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("unknown unit in '{}', use B, KB, MB or GB", input)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("'{}' is not a rate, e.g. 500KB/s", input))?;
+    let rate = (number * multiplier) as u64;
+    if rate == 0 {
+        return Err("the rate must be more than 0, use off to remove a limit".to_string());
+    }
+    Ok(Some(rate))
+}
+
+pub fn format_rate(rate: u64) -> String {
+    format!("{}/s", format_size(rate))
+}
+
+// Things held back by the throttle until their time comes, the event loop sleeps until the next one is due
+pub struct Delayed<T> {
+    items: Vec<(Instant, T)>,
+}
+
+impl<T> Default for Delayed<T> {
+    fn default() -> Self {
+        Delayed { items: Vec::new() }
+    }
+}
+
+impl<T> Delayed<T> {
+    pub fn push(&mut self, delay: Duration, item: T) {
+        self.items.push((Instant::now() + delay, item));
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.items.iter().map(|(at, _)| *at).min()
+    }
+
+    pub fn pop_due(&mut self) -> Vec<T> {
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = self.items.drain(..).partition(|(at, _)| *at <= now);
+        self.items = waiting;
+        due.into_iter().map(|(_, item)| item).collect()
+    }
+}
@@ -0,0 +1,426 @@
+use libp2p::request_response::OutboundRequestId;
+use libp2p::PeerId;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::downloads::{self, CollisionPolicy, DownloadOptions, FinishJob, Finishing};
+use super::links::SwapLink;
+use super::progress::ProgressMeter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("unknown priority '{}', use low, normal or high", s)),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "low"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::High => write!(f, "high"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    Queued,
+    Active,
+    Paused,
+    // all of it is here, it is being checked and moved into place
+    Finishing,
+    Completed,
+    Failed(String),
+    Cancelled,
+    // finished, but a different file with the same name is in the way, see /keep
+    Conflict(PathBuf),
+}
+
+impl fmt::Display for TransferState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferState::Queued => write!(f, "queued"),
+            TransferState::Active => write!(f, "active"),
+            TransferState::Paused => write!(f, "paused"),
+            TransferState::Finishing => write!(f, "finishing"),
+            TransferState::Completed => write!(f, "completed"),
+            TransferState::Failed(reason) => write!(f, "failed: {}", reason),
+            TransferState::Cancelled => write!(f, "cancelled"),
+            TransferState::Conflict(path) => write!(f, "{:?} already exists, see /keep", path),
+        }
+    }
+}
+
+pub struct Download {
+    pub peer: PeerId,
+    pub filename: String,
+    // where the file goes once it is complete
+    pub destination: PathBuf,
+    // where it is written to until then
+    pub part: PathBuf,
+    pub priority: Priority,
+    pub state: TransferState,
+    pub received: u64,
+    // not known until the first chunk arrives
+    pub total: Option<u64>,
+    // a chunk has been asked for, or is waiting on the throttle, so we mustn't ask for another
+    pub busy: bool,
+    pub meter: ProgressMeter,
+    // the folder or set of files this is part of, if it was asked for with /requestfiles
+    pub group: Option<u64>,
+    // sha256 of the file, when it was asked for by its contents with /get
+    pub hash: Option<String>,
+}
+
+impl Download {
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, TransferState::Completed | TransferState::Failed(_) | TransferState::Cancelled)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupState {
+    // waiting for the peer to say which files there are
+    Listing,
+    Listed,
+    Finished,
+    Failed(String),
+    Cancelled,
+}
+
+impl fmt::Display for GroupState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupState::Listing => write!(f, "waiting for the file list"),
+            GroupState::Listed => write!(f, "active"),
+            GroupState::Finished => write!(f, "finished"),
+            GroupState::Failed(reason) => write!(f, "failed: {}", reason),
+            GroupState::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+// Files asked for together, e.g. a whole folder. Each file is downloaded on its own through
+// the queue, the group keeps track of how they are getting on as a whole
+pub struct Group {
+    pub peer: PeerId,
+    // what was asked for, e.g. "photos, notes.txt"
+    pub name: String,
+    pub priority: Priority,
+    pub state: GroupState,
+    pub files: Vec<u64>,
+    pub received: u64,
+    pub total: u64,
+    pub meter: ProgressMeter,
+}
+
+// The download queue. Only a few downloads run at once, the rest wait their turn by priority
+// and then in the order they were asked for
+pub struct Transfers {
+    next_id: u64,
+    downloads: BTreeMap<u64, Download>,
+    // groups share their numbers with downloads, so /pause and friends work on either
+    groups: BTreeMap<u64, Group>,
+    outbound: HashMap<OutboundRequestId, u64>,
+    // file lists asked for, and the group each one is for
+    manifests: HashMap<OutboundRequestId, u64>,
+    max_active: usize,
+    options: DownloadOptions,
+    // completed downloads go to a worker thread to be checked and moved into place
+    finish_jobs: UnboundedSender<FinishJob>,
+}
+
+impl Transfers {
+    pub fn new(max_active: usize, options: DownloadOptions) -> (Self, UnboundedReceiver<Finishing>) {
+        let (finish_jobs, jobs) = mpsc::unbounded_channel();
+        let (finished, finishing) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || downloads::finish_worker(jobs, finished));
+        let transfers = Transfers {
+            next_id: 0,
+            downloads: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            outbound: HashMap::new(),
+            manifests: HashMap::new(),
+            max_active,
+            options,
+            finish_jobs,
+        };
+        (transfers, finishing)
+    }
+
+    pub fn options(&self) -> &DownloadOptions {
+        &self.options
+    }
+
+    pub fn enqueue(&mut self, peer: PeerId, filename: String, priority: Priority) -> u64 {
+        let destination = self.options.destination(&peer, &filename);
+        self.insert(peer, filename, destination, priority, None)
+    }
+
+    fn insert(&mut self, peer: PeerId, filename: String, destination: PathBuf, priority: Priority, group: Option<u64>) -> u64 {
+        self.next_id += 1;
+        self.downloads.insert(self.next_id, Download {
+            peer,
+            filename,
+            part: downloads::part_path(&destination, self.next_id),
+            destination,
+            priority,
+            state: TransferState::Queued,
+            received: 0,
+            total: None,
+            busy: false,
+            meter: ProgressMeter::new(0),
+            group,
+            hash: None,
+        });
+        self.next_id
+    }
+
+    // Queues a file from a swap link, it is asked for by its hash rather than its name
+    pub fn enqueue_link(&mut self, peer: PeerId, link: &SwapLink, priority: Priority) -> u64 {
+        let id = self.enqueue(peer, link.name.clone(), priority);
+        if let Some(download) = self.downloads.get_mut(&id) {
+            download.hash = Some(link.hash.clone());
+            download.total = Some(link.size);
+        }
+        id
+    }
+
+    // Starts a group, its files are added once the peer has sent the list of them
+    pub fn add_group(&mut self, peer: PeerId, name: String, priority: Priority) -> u64 {
+        self.next_id += 1;
+        self.groups.insert(self.next_id, Group {
+            peer,
+            name,
+            priority,
+            state: GroupState::Listing,
+            files: Vec::new(),
+            received: 0,
+            total: 0,
+            meter: ProgressMeter::new(0),
+        });
+        self.next_id
+    }
+
+    pub fn track_manifest(&mut self, request_id: OutboundRequestId, group: u64) {
+        self.manifests.insert(request_id, group);
+    }
+
+    pub fn finish_manifest(&mut self, request_id: &OutboundRequestId) -> Option<u64> {
+        self.manifests.remove(request_id)
+    }
+
+    // Queues a file from a group's list. `relative` is where it goes under the download folder,
+    // and must have been checked with manifest::safe_path
+    pub fn enqueue_in_group(&mut self, group: u64, filename: String, relative: &Path, size: u64) {
+        let Some(Group { peer, priority, .. }) = self.groups.get(&group) else {
+            return;
+        };
+        let (peer, priority) = (*peer, *priority);
+        let destination = self.options.destination_in(&peer, relative);
+        let id = self.insert(peer, filename, destination, priority, Some(group));
+        if let Some(download) = self.downloads.get_mut(&id) {
+            download.total = Some(size);
+        }
+        if let Some(group) = self.groups.get_mut(&group) {
+            group.files.push(id);
+            group.total += size;
+            if group.state == GroupState::Listing {
+                group.state = GroupState::Listed;
+                group.meter = ProgressMeter::new(0);
+            }
+        }
+    }
+
+    // Once the last file in a download's group is done, marks the group finished and returns
+    // the group's number along with how many of its files didn't make it
+    pub fn finish_group(&mut self, id: u64) -> Option<(u64, usize)> {
+        let group_id = self.downloads.get(&id)?.group?;
+        let group = self.groups.get_mut(&group_id)?;
+        if group.state != GroupState::Listed {
+            return None;
+        }
+        let mut failed = 0;
+        for file in &group.files {
+            let download = self.downloads.get(file)?;
+            match download.state {
+                TransferState::Completed | TransferState::Conflict(_) => {}
+                TransferState::Failed(_) | TransferState::Cancelled => failed += 1,
+                _ => return None,
+            }
+        }
+        group.state = GroupState::Finished;
+        Some((group_id, failed))
+    }
+
+    pub fn group(&self, id: u64) -> Option<&Group> {
+        self.groups.get(&id)
+    }
+
+    pub fn group_mut(&mut self, id: u64) -> Option<&mut Group> {
+        self.groups.get_mut(&id)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&u64, &Group)> {
+        self.groups.iter()
+    }
+
+    // Does the same thing to every file in a group, it works if it worked for any of them
+    fn each_in_group(&mut self, id: u64, action: fn(&mut Self, u64) -> Result<(), String>) -> Option<Result<(), String>> {
+        let files = self.groups.get(&id)?.files.clone();
+        let done = files.into_iter().filter(|file| action(self, *file).is_ok()).count();
+        Some(if done > 0 { Ok(()) } else { Err("none of its files can be".to_string()) })
+    }
+
+    // Marks queued downloads active while there is room, returning the ones that need a chunk asked for
+    pub fn start_queued(&mut self) -> Vec<u64> {
+        let active = self.downloads.values().filter(|d| d.state == TransferState::Active).count();
+        let mut queued: Vec<(Priority, u64)> = self.downloads
+            .iter()
+            .filter(|(_, d)| d.state == TransferState::Queued)
+            .map(|(id, d)| (d.priority, *id))
+            .collect();
+        // highest priority first, oldest first within a priority
+        queued.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let mut start = Vec::new();
+        for (_, id) in queued.into_iter().take(self.max_active.saturating_sub(active)) {
+            let download = self.downloads.get_mut(&id).expect("queued download exists");
+            download.state = TransferState::Active;
+            download.meter = ProgressMeter::new(download.received);
+            // one that was paused and resumed may still have a chunk on the way, it carries on from that
+            if !download.busy {
+                start.push(id);
+            }
+        }
+        start
+    }
+
+    pub fn track(&mut self, request_id: OutboundRequestId, id: u64) {
+        self.outbound.insert(request_id, id);
+        if let Some(download) = self.downloads.get_mut(&id) {
+            download.busy = true;
+        }
+    }
+
+    // Matches a response or failure back to its download, which is no longer waiting on a chunk
+    pub fn finish_request(&mut self, request_id: &OutboundRequestId) -> Option<u64> {
+        let id = self.outbound.remove(request_id)?;
+        if let Some(download) = self.downloads.get_mut(&id) {
+            download.busy = false;
+        }
+        Some(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Download> {
+        self.downloads.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Download> {
+        self.downloads.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u64, &Download)> {
+        self.downloads.iter()
+    }
+
+    pub fn pause(&mut self, id: u64) -> Result<(), String> {
+        if let Some(result) = self.each_in_group(id, Self::pause) {
+            return result;
+        }
+        let download = self.downloads.get_mut(&id).ok_or("no such transfer")?;
+        match download.state {
+            TransferState::Queued | TransferState::Active => {
+                download.state = TransferState::Paused;
+                Ok(())
+            }
+            ref state => Err(format!("transfer is {}", state)),
+        }
+    }
+
+    // Paused and failed downloads go back in the queue, and carry on from where they got to
+    pub fn resume(&mut self, id: u64) -> Result<(), String> {
+        if let Some(result) = self.each_in_group(id, Self::resume) {
+            return result;
+        }
+        let download = self.downloads.get_mut(&id).ok_or("no such transfer")?;
+        match download.state {
+            TransferState::Paused | TransferState::Failed(_) => {
+                // the part file is thrown away when it fails its hash check, so start again from the beginning
+                if !download.part.exists() {
+                    download.received = 0;
+                    download.total = None;
+                }
+                download.state = TransferState::Queued;
+                Ok(())
+            }
+            ref state => Err(format!("transfer is {}", state)),
+        }
+    }
+
+    // Checks a completed download and moves it into place on the finish worker, the event loop
+    // picks up how it went from the receiver returned by new
+    pub fn finish_in_background(&mut self, id: u64, policy: CollisionPolicy, kept: bool, elapsed: Duration) {
+        let Some(download) = self.downloads.get_mut(&id) else {
+            return;
+        };
+        download.state = TransferState::Finishing;
+        let job = FinishJob {
+            id,
+            kept,
+            elapsed,
+            part: download.part.clone(),
+            destination: download.destination.clone(),
+            policy,
+            // a file kept after a conflict was already checked the first time round
+            hash: download.hash.clone().filter(|_| !kept),
+        };
+        let _ = self.finish_jobs.send(job);
+    }
+
+    // Returns the part files that were being written, nothing else will finish them
+    pub fn cancel(&mut self, id: u64) -> Result<Vec<PathBuf>, String> {
+        if let Some(group) = self.groups.get_mut(&id) {
+            if matches!(group.state, GroupState::Finished | GroupState::Failed(_) | GroupState::Cancelled) {
+                return Err(format!("transfer is {}", group.state));
+            }
+            group.state = GroupState::Cancelled;
+            let files = group.files.clone();
+            return Ok(files.into_iter().filter_map(|file| self.cancel(file).ok()).flatten().collect());
+        }
+        let download = self.downloads.get_mut(&id).ok_or("no such transfer")?;
+        // one being finished is already being moved into place
+        if download.is_finished() || download.state == TransferState::Finishing {
+            return Err(format!("transfer is {}", download.state));
+        }
+        download.state = TransferState::Cancelled;
+        Ok(vec![download.part.clone()])
+    }
+
+    // What a download or group is doing, for messages about it
+    pub fn describe(&self, id: u64) -> String {
+        match (self.downloads.get(&id), self.groups.get(&id)) {
+            (Some(download), _) => download.state.to_string(),
+            (None, Some(group)) => group.state.to_string(),
+            (None, None) => String::new(),
+        }
+    }
+}
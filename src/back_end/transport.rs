@@ -0,0 +1,67 @@
+use either::Either;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OrTransport};
+use libp2p::core::upgrade::Version;
+use libp2p::identity::Keypair;
+use libp2p::pnet::{PnetConfig, PreSharedKey};
+use libp2p::{noise, quic, tcp, yamux, PeerId, Transport};
+use rand::RngCore;
+use std::error::Error;
+use std::path::Path;
+
+use super::persistent_store;
+
+// Builds the transport stack. Normally that is TCP and QUIC, but with a swarm key only TCP is used,
+// wrapped in the pre-shared key handshake so nodes without the key can't connect at all.
+// QUIC brings its own encryption and can't be wrapped, so it is left out of private networks.
+pub fn build_transport(
+    key: &Keypair,
+    psk: Option<PreSharedKey>,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let tcp_transport = tcp::tokio::Transport::new(tcp::Config::default());
+    let tcp_transport = match psk {
+        Some(psk) => Either::Left(
+            tcp_transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+        ),
+        None => Either::Right(tcp_transport),
+    }
+    .upgrade(Version::V1Lazy)
+    .authenticate(noise::Config::new(key)?)
+    .multiplex(yamux::Config::default())
+    .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+
+    if psk.is_some() {
+        return Ok(tcp_transport.boxed());
+    }
+
+    let quic_transport = quic::tokio::Transport::new(quic::Config::new(key))
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+    Ok(OrTransport::new(tcp_transport, quic_transport)
+        .map(|either, _| either.into_inner())
+        .boxed())
+}
+
+pub fn load_swarm_key(path: &Path) -> Result<PreSharedKey, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read swarm key {:?}: {}", path, e))?;
+    let psk = contents.parse::<PreSharedKey>()
+        .map_err(|e| format!("Invalid swarm key {:?}: {}", path, e))?;
+    Ok(psk)
+}
+
+// Writes a new random swarm key, in the same format as go-ipfs uses, for sharing with the rest of the group
+pub fn generate_swarm_key(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let psk = PreSharedKey::new(bytes);
+    // anyone with the key can join, so only we get to read it, and an existing key is never replaced
+    match persistent_store::write_secret(path, format!("{}\n", psk).as_bytes()) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(format!("{:?} already exists, not overwriting it", path).into());
+        }
+        result => result?,
+    }
+    println!("Wrote a new swarm key to {:?} (fingerprint {})", path, psk.fingerprint());
+    println!("Give this file to everyone in your group, and start with --swarm-key {:?}", path);
+    Ok(())
+}
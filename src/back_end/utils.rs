@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 use regex::Regex;
 
-const ALLOWED_TOPICS: [&str; 4] = ["chat", "movies", "books", "music"];
+pub const ALLOWED_TOPICS: [&str; 4] = ["chat", "movies", "books", "music"];
 
 pub fn split_string(input: &str) -> Vec<String> {
     let re = Regex::new(r#""([^"]*)"|\S+"#).unwrap();
@@ -10,6 +10,14 @@ pub fn split_string(input: &str) -> Vec<String> {
         .collect()
 }
 
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1}MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1}KB", b as f64 / 1024.0),
+        b => format!("{}B", b),
+    }
+}
+
 pub fn check_topic(topic: &str) -> bool {
     let allowed_topics: HashSet<&str> = ALLOWED_TOPICS.iter().cloned().collect();
     allowed_topics.contains(topic)
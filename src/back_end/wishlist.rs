@@ -0,0 +1,367 @@
+use libp2p::{gossipsub, PeerId, Swarm};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::behaviour::ChatBehaviour;
+use super::gossip::{RateLimiter, MAX_MESSAGE_SIZE};
+use super::links::{self, SwapLink};
+use super::manifest;
+use super::persistent_store::write_json;
+use super::shares::ShareIndex;
+use super::state::ChatState;
+use super::utils::format_size;
+
+// Wishlists and offers go out on their own topic, which every node joins whatever room it is chatting in
+pub const TOPIC: &str = "wishlists";
+// How often our wishlist is sent out again, so peers who join later hear about it
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// A peer's wishlist is forgotten if they stop sending it
+const PEER_WISHLIST_TTL: Duration = Duration::from_secs(3 * 5 * 60);
+const WISHLIST_FILE: &str = "wishlist.json";
+const MAX_WANTS: usize = 20;
+// Most files listed in one offer, it has to fit in a gossip message
+const MAX_OFFERED: usize = 10;
+// Wishlist messages have their own rate limit, separate from chat. We send ours no faster than one every
+// SEND_INTERVAL, comfortably under it, however many peers we have offers for
+const RATE_LIMIT_MESSAGES: usize = 10;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(30);
+const SEND_INTERVAL: Duration = Duration::from_secs(5);
+// Changes to the wishlist are sent once the user has stopped making them for this long
+const BROADCAST_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Want {
+    // a file with all of these words in its name
+    Keywords(Vec<String>),
+    // a particular file, e.g. from a swap link
+    Hash { hash: String, name: Option<String> },
+}
+
+impl fmt::Display for Want {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Want::Keywords(keywords) => write!(f, "\"{}\"", keywords.join(" ")),
+            Want::Hash { hash, name: Some(name) } => write!(f, "{} ({})", name, hash.get(..16).unwrap_or(hash)),
+            Want::Hash { hash, name: None } => write!(f, "file {}", hash.get(..16).unwrap_or(hash)),
+        }
+    }
+}
+
+impl Want {
+    // Parses what was typed after /want: a swap link, a hash, or keywords
+    pub fn parse(args: &[String]) -> Result<Want, String> {
+        let args: Vec<&str> = args.iter().map(|a| a.trim_matches('"')).filter(|a| !a.is_empty()).collect();
+        match args.as_slice() {
+            [] => Err("Please say what you want, as keywords, a hash or a swap link".to_string()),
+            [link] if link.starts_with("swap://") => {
+                let link = SwapLink::from_str(link).map_err(|e| format!("Invalid swap link: {}", e))?;
+                Ok(Want::Hash { hash: link.hash, name: Some(link.name) })
+            }
+            [hash] if links::is_hash(hash) => Ok(Want::Hash { hash: hash.to_ascii_lowercase(), name: None }),
+            keywords => Ok(Want::Keywords(keywords.iter().map(|k| k.to_lowercase()).collect())),
+        }
+    }
+
+    // Our hashed files that would do, only hashed ones as the offer has to say how to get them
+    fn matches<'a>(&'a self, shares: &'a ShareIndex) -> Vec<(&'a String, u64, &'a String)> {
+        let hashed = |(name, file): (&'a String, &'a super::shares::SharedFile)| file.hash.as_ref().map(|hash| (name, file.size, hash));
+        match self {
+            Want::Keywords(keywords) => shares.search(keywords).filter_map(hashed).collect(),
+            Want::Hash { hash, .. } => shares.find_hash(hash).and_then(hashed).into_iter().collect(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            Want::Keywords(keywords) => !keywords.is_empty() && keywords.iter().all(|k| !k.is_empty() && is_printable(k)),
+            Want::Hash { hash, name } => links::is_hash(hash) && name.as_deref().is_none_or(is_printable),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfferedFile {
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+// Files that match one of the wants
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfferMatch {
+    pub want: Want,
+    pub files: Vec<OfferedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WishlistMessage {
+    // everything the author wants
+    Wants(Vec<Want>),
+    // files the author has that `to` asked for, all of them in one message
+    Offer { to: String, matches: Vec<OfferMatch> },
+}
+
+fn is_printable(s: &str) -> bool {
+    !s.chars().any(char::is_control)
+}
+
+// Checks a message on the wishlist topic is something our own client could have sent
+pub fn check_message(data: &[u8]) -> Result<WishlistMessage, &'static str> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err("message too large");
+    }
+    let message: WishlistMessage = serde_json::from_slice(data).map_err(|_| "not a wishlist message")?;
+    let valid = match &message {
+        WishlistMessage::Wants(wants) => wants.len() <= MAX_WANTS && wants.iter().all(Want::is_valid),
+        WishlistMessage::Offer { to, matches } => {
+            PeerId::from_str(to).is_ok()
+                && !matches.is_empty()
+                && matches.iter().map(|m| m.files.len()).sum::<usize>() <= MAX_OFFERED
+                && matches.iter().all(|m| {
+                    m.want.is_valid()
+                        && !m.files.is_empty()
+                        && m.files.iter().all(|f| links::is_hash(&f.hash) && manifest::safe_path(&f.name).is_some() && is_printable(&f.name))
+                })
+        }
+    };
+    match valid {
+        true => Ok(message),
+        false => Err("invalid wishlist message"),
+    }
+}
+
+// Our own wishlist, kept in the data directory, and what we have heard other peers want
+pub struct Wishlist {
+    path: PathBuf,
+    wants: Vec<Want>,
+    peers: HashMap<PeerId, (Vec<Want>, Instant)>,
+    // files already offered to a peer, or offers already shown to us, so the repeats don't fill the chat
+    offered: HashSet<(PeerId, String)>,
+    seen_offers: HashSet<(PeerId, String)>,
+    // how often each peer has sent wishlist messages
+    pub rate_limiter: RateLimiter,
+    // messages waiting to go out, one every SEND_INTERVAL
+    outbox: VecDeque<WishlistMessage>,
+    last_sent: Option<tokio::time::Instant>,
+    // when to send the wishlist after it was changed
+    broadcast_at: Option<tokio::time::Instant>,
+}
+
+impl Wishlist {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(WISHLIST_FILE);
+        let mut wants: Vec<Want> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                eprintln!("Warning: could not read the wishlist, starting empty - {}", e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        // the file may have been edited by hand, held to the same rules as wants from other peers
+        let loaded = wants.len();
+        wants.retain(Want::is_valid);
+        wants.truncate(MAX_WANTS);
+        if wants.len() < loaded {
+            eprintln!("Warning: left out {} wishlist entries that were invalid or over the limit", loaded - wants.len());
+        }
+        Wishlist {
+            path,
+            wants,
+            peers: HashMap::new(),
+            offered: HashSet::new(),
+            seen_offers: HashSet::new(),
+            rate_limiter: RateLimiter::new(RATE_LIMIT_MESSAGES, RATE_LIMIT_WINDOW),
+            outbox: VecDeque::new(),
+            last_sent: None,
+            broadcast_at: None,
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = write_json(&self.path, &self.wants) {
+            eprintln!("Failed to save the wishlist: {}", e);
+        }
+    }
+
+    pub fn add(&mut self, want: Want) -> Result<(), String> {
+        if self.wants.contains(&want) {
+            return Err(format!("{} is already on your wishlist", want));
+        }
+        if self.wants.len() >= MAX_WANTS {
+            return Err(format!("Your wishlist is full, it can hold {} things", MAX_WANTS));
+        }
+        self.wants.push(want);
+        if encode(&WishlistMessage::Wants(self.wants.clone())).len() > MAX_MESSAGE_SIZE {
+            self.wants.pop();
+            return Err("Your wishlist is too long to send, remove something first".to_string());
+        }
+        self.save();
+        Ok(())
+    }
+
+    // Removes the want with the number shown in /wants, counting from 1
+    pub fn remove(&mut self, number: usize) -> Option<Want> {
+        if number == 0 || number > self.wants.len() {
+            return None;
+        }
+        let want = self.wants.remove(number - 1);
+        self.save();
+        Some(want)
+    }
+
+    // Sends the wishlist a little later, so a burst of /want and /unwant only goes out once
+    pub fn schedule_broadcast(&mut self) {
+        self.broadcast_at = Some(tokio::time::Instant::now() + BROADCAST_DELAY);
+    }
+
+    pub fn broadcast_at(&self) -> Option<tokio::time::Instant> {
+        self.broadcast_at
+    }
+
+    // When the next queued message can go out, if there is one
+    pub fn next_send(&self) -> Option<tokio::time::Instant> {
+        if self.outbox.is_empty() {
+            return None;
+        }
+        let now = tokio::time::Instant::now();
+        Some(self.last_sent.map_or(now, |sent| (sent + SEND_INTERVAL).max(now)))
+    }
+
+    fn queue(&mut self, message: WishlistMessage) {
+        // an older copy of our wishlist still waiting is out of date, replace it
+        if let WishlistMessage::Wants(_) = message {
+            if let Some(queued) = self.outbox.iter_mut().find(|m| matches!(m, WishlistMessage::Wants(_))) {
+                *queued = message;
+                return;
+            }
+        }
+        self.outbox.push_back(message);
+    }
+
+    pub fn wants(&self) -> &[Want] {
+        &self.wants
+    }
+
+    // What other peers want, leaving out anyone we haven't heard from in a while
+    pub fn peer_wants(&self) -> impl Iterator<Item = (&PeerId, &Vec<Want>)> {
+        self.peers
+            .iter()
+            .filter(|(_, (_, heard))| heard.elapsed() < PEER_WISHLIST_TTL)
+            .map(|(peer, (wants, _))| (peer, wants))
+    }
+}
+
+fn encode(message: &WishlistMessage) -> Vec<u8> {
+    serde_json::to_vec(message).expect("wishlist messages can always be encoded")
+}
+
+// Sends the next queued message, called when next_send() comes round
+pub fn send_next(swarm: &mut Swarm<ChatBehaviour>, state: &mut ChatState) {
+    let Some(message) = state.wishlist.outbox.pop_front() else {
+        return;
+    };
+    state.wishlist.last_sent = Some(tokio::time::Instant::now());
+    let topic = gossipsub::IdentTopic::new(TOPIC);
+    match swarm.behaviour_mut().gossipsub.publish(topic, encode(&message)) {
+        // nobody else on the topic yet, they will get it next time round
+        Ok(_) | Err(gossipsub::PublishError::InsufficientPeers) => {}
+        Err(e) => eprintln!("Failed to send wishlist message: {:?}", e),
+    }
+}
+
+// Queues our wishlist, and offers anything we have to peers who want it. Run every PUBLISH_INTERVAL
+// and shortly after the wishlist changes
+pub fn broadcast(state: &mut ChatState) {
+    state.wishlist.broadcast_at = None;
+    if !state.wishlist.wants.is_empty() {
+        let wants = WishlistMessage::Wants(state.wishlist.wants.clone());
+        state.wishlist.queue(wants);
+    }
+    // files may have been added to the shares since we last looked
+    state.wishlist.peers.retain(|_, (_, heard)| heard.elapsed() < PEER_WISHLIST_TTL);
+    let peers: Vec<PeerId> = state.wishlist.peers.keys().cloned().collect();
+    for peer in peers {
+        offer_matches(state, peer);
+    }
+}
+
+pub fn handle_message(state: &mut ChatState, author: PeerId, message: WishlistMessage) {
+    match message {
+        WishlistMessage::Wants(wants) => {
+            state.wishlist.peers.insert(author, (wants, Instant::now()));
+            offer_matches(state, author);
+        }
+        WishlistMessage::Offer { to, matches } => {
+            if to != state.self_peer_id.to_string() {
+                return;
+            }
+            let name = state.contacts.display_name(&author);
+            for OfferMatch { want, files } in matches {
+                for file in files {
+                    if !state.wishlist.seen_offers.insert((author, file.hash.clone())) {
+                        continue;
+                    }
+                    let filename = file.name.rsplit('/').next().unwrap_or(&file.name).to_string();
+                    let link = SwapLink { hash: file.hash, size: file.size, provider: Some(author), name: filename };
+                    println!("{} has {} ({}) from your wishlist ({}), get it with /get {}", name, file.name, format_size(file.size), want, link);
+                }
+            }
+        }
+    }
+}
+
+// Tells a peer about any of our files on their wishlist in a single offer, and lets the user know someone wants them
+fn offer_matches(state: &mut ChatState, peer: PeerId) {
+    if state.blocklist.contains(&peer) {
+        return;
+    }
+    let Some((wants, _)) = state.wishlist.peers.get(&peer) else {
+        return;
+    };
+    let mut matches: Vec<OfferMatch> = Vec::new();
+    let mut count = 0;
+    for want in wants {
+        for (name, size, hash) in want.matches(&state.shares) {
+            if count == MAX_OFFERED {
+                break;
+            }
+            // a file matching two wants is only offered once
+            let already = matches.iter().any(|m| m.files.iter().any(|f| &f.hash == hash));
+            if already || state.wishlist.offered.contains(&(peer, hash.clone())) {
+                continue;
+            }
+            let file = OfferedFile { name: name.clone(), size, hash: hash.clone() };
+            match matches.iter_mut().find(|m| &m.want == want) {
+                Some(m) => m.files.push(file),
+                None => matches.push(OfferMatch { want: want.clone(), files: vec![file] }),
+            }
+            // long file names could take it over the limit, the rest are offered next time round
+            if encode(&WishlistMessage::Offer { to: peer.to_string(), matches: matches.clone() }).len() > MAX_MESSAGE_SIZE {
+                let last = matches.last_mut().expect("a file was just added");
+                last.files.pop();
+                if last.files.is_empty() {
+                    matches.pop();
+                }
+                count = MAX_OFFERED;
+                break;
+            }
+            count += 1;
+        }
+    }
+    if matches.is_empty() {
+        return;
+    }
+    let peer_name = state.contacts.display_name(&peer);
+    for m in &matches {
+        let names: Vec<&str> = m.files.iter().map(|f| f.name.as_str()).collect();
+        println!("{} wants {}, you have {}", peer_name, m.want, names.join(", "));
+        for file in &m.files {
+            state.wishlist.offered.insert((peer, file.hash.clone()));
+        }
+    }
+    state.wishlist.queue(WishlistMessage::Offer { to: peer.to_string(), matches });
+}
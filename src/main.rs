@@ -2,11 +2,17 @@ mod back_end;
 use back_end::behaviour;
 use back_end::utils;
 use back_end::swarm_builder;
+use back_end::cli::Cli;
+use clap::Parser;
 use std::error::Error;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    swarm_builder::start_swarm_builder().await
+    let cli = Cli::parse();
+    if let Some(path) = &cli.generate_swarm_key {
+        return back_end::transport::generate_swarm_key(path);
+    }
+    swarm_builder::start_swarm_builder(cli).await
 }
 
 